@@ -2,6 +2,7 @@ use lemondb::{
     LemonDb,
     LemonOption,
     LemonDumpRule,
+    LemonStorageMode,
     Serializer
 };
 
@@ -27,9 +28,11 @@ fn main() {
         LemonOption {
            serializer: Serializer::YAML,
            dump_rule: LemonDumpRule::AUTO,
-           table_name: None
+           table_name: None,
+           storage: LemonStorageMode::SNAPSHOT,
+           lock_timeout: None
         }
-    );
+    ).unwrap();
 
 
     db.insert::<User>("user", &u).unwrap();
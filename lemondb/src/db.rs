@@ -24,21 +24,43 @@ use std::{
         Path
     }, 
     time::{Duration, Instant}, 
-    collections::HashMap
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Bound,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
-use serde::Serialize;
-use anyhow::{Result, Context};
+use serde::{Serialize, de::DeserializeOwned};
+use anyhow::{Result, Context, bail};
 
 use crate::{
-    storage::LemonStorage, 
-    document::LemonDocument, 
+    storage::{LemonStorage, LemonLock, LogRecord, table_mut}, 
+    document::{LemonDocument, decode_fields}, 
+    index::{LemonIndex, IndexDefinition, IndexKind, INDEX_TABLE, field_value, index_key},
+    error::LemonError,
+    id::LemonId,
+    query::{Cursor, Query}, 
+    utils::now_timestamp,
     Serializer, 
     LemonSerializer,
 };
 
 type Data = HashMap<String, Vec<u8>>;
 type Document = HashMap<String, Data>;
-type Table = HashMap<String, Document>;
+type Documents<'s> = Box<dyn Iterator<Item = (&'s String, &'s Data)> + 's>;
+
+/// The reserved table holding the expiry time of the documents
+/// inserted with a ttl, keyed by the document id.
+const EXPIRY_TABLE: &str = "__lemon_expiry";
+
+/// The reserved table holding the default ttl of the tables,
+/// keyed by the table name.
+const TTL_TABLE: &str = "__lemon_ttl";
+
+/// Compact the log once the records appended since the last snapshot
+/// take as many bytes as the snapshot itself
+const DEFAULT_COMPACTION_RATIO: f64 = 1.0;
+
+/// Don't bother compacting a log with fewer appended bytes than this
+const MIN_APPENDED_BYTES: u64 = 64 * 1024;
 
 
 /// The lemon dump rule for dumping the database.
@@ -52,6 +74,21 @@ pub enum LemonDumpRule {
     PERIODIC(Duration)
 }
 
+/// The lemon storage mode of the database file.
+/// SNAPSHOT - Every dump rewrites the whole database to the file
+/// LOG - Every change is appended to the file as a record, so a dump only
+/// writes what changed since the last one. The records are replayed by `open`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LemonStorageMode {
+    SNAPSHOT,
+    LOG,
+}
+
+/// A handle to the database bound to a table.
+///
+/// Cloning a `LemonDb` or calling `table` doesn't copy the data, every
+/// handle shares the same in-memory state and storage. So a write from
+/// one handle is visible from all the others.
 #[derive(Debug, Clone)]
 pub struct LemonDb {
     // Set the database path and table name
@@ -59,20 +96,51 @@ pub struct LemonDb {
     pub db_path: PathBuf,
     pub table: String,
 
+    inner: Arc<RwLock<LemonState>>,
+    serializer: LemonSerializer,
+}
+
+/// The state shared between every handle of the same database.
+#[derive(Debug)]
+struct LemonState {
     map: Vec<
         HashMap<String, Document>
     >,
     storage: LemonStorage,
-    dump_rule: LemonDumpRule,
     serializer: LemonSerializer,
+    indexes: Vec<LemonIndex>,
+    /// The expiry time of a document in milliseconds since the unix epoch
+    expiry: HashMap<String, u64>,
+    /// The table of every document with an expiry, ordered by the expiry
+    /// so a purge only looks at the expired documents
+    deadlines: BTreeMap<(u64, String), String>,
+    /// The default ttl of a table in milliseconds
+    ttls: HashMap<String, u64>,
+    /// The documents of the tables with a ttl ordered by creation time,
+    /// except the ones with their own expiry
+    created: HashMap<String, BTreeSet<(u64, String)>>,
+    dump_rule: LemonDumpRule,
     last_dump: Instant,
+    /// The changes to be appended to the log on the next dump
+    pending: Vec<LogRecord>,
+    /// Start the log over from a snapshot on the next dump
+    rewrite: bool,
+    compaction_ratio: Option<f64>,
+    /// Held until the last handle is dropped
+    _lock: LemonLock,
 }
 
 #[derive(Debug, Clone)]
 pub struct LemonOption {
     pub table_name: Option<&'static str>,
     pub dump_rule: LemonDumpRule,
+    /// The serializer of a new database. `open` takes it from the header
+    /// of the file and only tries this one first on files without one.
     pub serializer: Serializer,
+    pub storage: LemonStorageMode,
+    /// How long to wait for another process to release the database,
+    /// `None` fails right away with `LemonError::Locked`.
+    pub lock_timeout: Option<Duration>,
 }
 
 impl LemonDb {
@@ -104,6 +172,9 @@ impl LemonDb {
     /// |-----------------------------------------------|
     ///  \----------------------------------------------\
     ///
+    /// The database is locked until every handle of it is dropped. Opening
+    /// it again fails with `LemonError::Locked`, even from the same process,
+    /// or waits for `lock_timeout`. Clone the handle or use `table` instead.
     /// 
     /// # Arguments
     ///
@@ -113,9 +184,10 @@ impl LemonDb {
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```ignore
     /// use lemondb::{LemonDb, LemonOption,
     ///   LemonDumpRule,
+    ///   LemonStorageMode,
     ///   Serializer
     /// };
     ///
@@ -126,36 +198,55 @@ impl LemonDb {
     ///     table_name: None,
     ///     dump_rule: LemonDumpRule::AUTO,
     ///     serializer: Serializer::JSON,
+    ///     storage: LemonStorageMode::SNAPSHOT,
+    ///     lock_timeout: None,
     ///   }
-    /// );
+    /// ).unwrap();
     /// ...
     ///
     /// ```
     pub fn new<P: AsRef<Path>>(
         db_path: P,
         option: LemonOption
-    ) -> LemonDb {
+    ) -> Result<LemonDb> {
         
         let db_path_buf = PathBuf::new().join(db_path); 
 
-        let s = LemonStorage::new(db_path_buf.clone(), option.serializer.clone());
-        let table_name = option.table_name.or(Some("_table")).unwrap();
+        let table_name = option.table_name.unwrap_or("_table");
+        if is_reserved(table_name) {
+            bail!("Table `{}` is reserved", table_name);
+        }
+
+        let lock = LemonLock::acquire(&db_path_buf, option.lock_timeout)?;
+        let s = LemonStorage::new(db_path_buf.clone(), option.serializer.clone(), option.storage);
 
         let empty_map: Document = HashMap::new();
         let mut map: HashMap<String, Document> = HashMap::new();
         map.insert(table_name.to_string(), empty_map);
 
 
-        LemonDb {
+        Ok(LemonDb {
             db_path: db_path_buf,
             // Set the default table name to _table
             table: table_name.to_string(),
-            map: vec![map],
-            storage: s,
+            inner: Arc::new(RwLock::new(LemonState {
+                map: vec![map],
+                storage: s,
+                serializer: LemonSerializer::new(option.serializer.clone()),
+                indexes: Vec::new(),
+                expiry: HashMap::new(),
+                deadlines: BTreeMap::new(),
+                ttls: HashMap::new(),
+                created: HashMap::new(),
+                dump_rule: option.dump_rule,
+                last_dump: Instant::now(),
+                pending: Vec::new(),
+                rewrite: true,
+                compaction_ratio: Some(DEFAULT_COMPACTION_RATIO),
+                _lock: lock,
+            })),
             serializer: LemonSerializer::new(option.serializer),
-            dump_rule: option.dump_rule,
-            last_dump: Instant::now(),
-        }
+        })
     }
     
     /// Load the DB from the file
@@ -163,6 +254,11 @@ impl LemonDb {
     /// This function open and load the file & deserialize it then return a structured data 
     /// of the database. The function return a Result of LemonDb instance.
     ///
+    /// The serializer is detected from the header of the file, so a YAML
+    /// database can be opened with `Serializer::JSON`. Files written by a
+    /// newer format version fail with `LemonError::UnsupportedVersion`.
+    /// The database is locked like with `new`.
+    ///
     /// # Arguments
     ///
     /// * `dp_path` - The path of the database to be loaded
@@ -170,13 +266,15 @@ impl LemonDb {
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```ignore
     /// let db = LemonDb::open(
     ///   "test.db",
     ///   LemonOption {
     ///     table_name: None,
     ///     dump_rule: LemonDumpRule::AUTO,
-    ///     serializer: Serializer::JSON
+    ///     serializer: Serializer::JSON,
+    ///     storage: LemonStorageMode::SNAPSHOT,
+    ///     lock_timeout: None,
     ///   }
     /// ).unwrap()
     /// ...
@@ -187,29 +285,109 @@ impl LemonDb {
         option: LemonOption,
     ) -> Result<LemonDb> 
     {
+        let (db, _) = LemonDb::load(db_path, option, false)?;
+        Ok(db)
+    }
+
+    /// ### recover `fn`
+    ///
+    /// Load the DB from the file like `open`, but keep everything read up
+    /// to the first corrupted frame instead of failing. Return the corruption
+    /// that was skipped, if any, with the byte where it starts.
+    ///
+    /// The file is left as it is until the next dump replaces it with a
+    /// snapshot of what was kept, `compact` does it right away.
+    ///
+    /// A crash in the middle of a dump in `LemonStorageMode::LOG` leaves an
+    /// incomplete frame at the end of the file, which `open` refuses as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `dp_path` - The path of the database to be loaded
+    /// * `option`  - The init option of the databse `LemonOption`
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let db = match LemonDb::open("test.db", option.clone()) {
+    ///     Ok(db) => db,
+    ///     Err(err) => {
+    ///         eprintln!("{:#}", err);
+    ///         let (db, corruption) = LemonDb::recover("test.db", option).unwrap();
+    ///         db
+    ///     },
+    /// };
+    /// ```
+    ///
+    pub fn recover<P: AsRef<Path>>(
+        db_path: P,
+        option: LemonOption,
+    ) -> Result<(LemonDb, Option<LemonError>)> 
+    {
+        LemonDb::load(db_path, option, true)
+    }
+
+    fn load<P: AsRef<Path>>(
+        db_path: P,
+        option: LemonOption,
+        recover: bool,
+    ) -> Result<(LemonDb, Option<LemonError>)> 
+    {
+        let table_name = option.table_name.unwrap_or("_table");
+        if is_reserved(table_name) {
+            bail!("Table `{}` is reserved", table_name);
+        }
+
         let db_path_buf = PathBuf::new().join(db_path.as_ref());
-        let s = LemonStorage::new(&db_path, option.serializer.clone());
-        let content = s.read()
+        // Lock before reading, another process may be in the middle of a dump
+        let lock = LemonLock::acquire(&db_path_buf, option.lock_timeout)?;
+        let mut s = LemonStorage::new(&db_path, option.serializer.clone(), option.storage);
+        let content = s.read(recover)
             .context("Failed to read the database. It's either doenst exist or not a database object")?;
         
-        let table_name = option.table_name.or(Some("_table")).unwrap();
-        Ok(
+        let mut state = LemonState {
+            map: content.tables,
+            storage: s,
+            serializer: LemonSerializer::new(content.serializer.clone()),
+            indexes: Vec::new(),
+            expiry: HashMap::new(),
+            deadlines: BTreeMap::new(),
+            ttls: HashMap::new(),
+            created: HashMap::new(),
+            dump_rule: option.dump_rule,
+            last_dump: Instant::now(),
+            pending: Vec::new(),
+            // Don't append after a corrupted frame or to a file without header
+            rewrite: content.corruption.is_some() || content.legacy,
+            compaction_ratio: Some(DEFAULT_COMPACTION_RATIO),
+            _lock: lock,
+        };
+        state.load_indexes()
+            .context("Failed to rebuild the indexes")?;
+        state.load_expiry()
+            .context("Failed to load the expiry of the documents")?;
+
+        Ok((
             LemonDb {
                 db_path: db_path_buf,
                 table: table_name.to_string(),
-                map: content,
-                storage: s,
-                serializer: LemonSerializer::new(option.serializer),
-                dump_rule: option.dump_rule,
-                last_dump: Instant::now(),
-            }
-        )
+                inner: Arc::new(RwLock::new(state)),
+                serializer: LemonSerializer::new(content.serializer),
+            },
+            content.corruption,
+        ))
 
     }
 
     /// ### table `fn`
     ///
-    /// Return a copy of the database given by the table name
+    /// Return a handle of the database bound to the given table name. The
+    /// table is created if it doesn't exist yet. The tables holding the
+    /// database metadata are reserved and can't be used.
+    ///
+    /// The handle shares the same state and storage with every other handle,
+    /// so any change made through it is visible from the parent database.
     ///
     /// # Arguments
     ///
@@ -218,7 +396,7 @@ impl LemonDb {
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```ignore
     ///
     /// let mut db = LemonDb(...);
     /// 
@@ -226,34 +404,171 @@ impl LemonDb {
     /// db.insert::<String>("hello", &String::from("world")).unwrap();
     ///
     /// 
-    /// let mut user = db.table("user");
+    /// let mut user = db.table("user").unwrap();
     ///
     /// // Insert the data to the user table
     /// user.insert::<String>("name"d, &"John Doe".to_string()).unwrap();
     ///
+    /// // Visible from the parent database as well
+    /// db.table("user").unwrap().get::<String>("name").unwrap();
+    ///
     /// ```
     ///
-    pub fn table(&mut self, name: &str) -> Self {
+    pub fn table(&self, name: &str) -> Result<Self> {
+        if is_reserved(name) {
+            bail!("Table `{}` is reserved", name);
+        }
         
         // Check if the table name already exist. If not then
         // create a empty table
-        for i in self.map.iter_mut() {
-            if !i.contains_key(name) {
-                let temp: Document = HashMap::new();
-                i.insert(name.to_string(), temp);
-            }
-        }
+        self.state_mut().table_mut(name);
 
-        Self {
+        Ok(Self {
             db_path: self.db_path.clone(),
             table: name.to_string(),
-            map: self.map.clone(),
-            storage: self.storage.clone(),
+            inner: Arc::clone(&self.inner),
             serializer: self.serializer.clone(),
-            dump_rule: self.dump_rule.clone(),
-            last_dump: self.last_dump.clone(),
+        })
+
+    }
+
+    /// ### tables `fn`
+    ///
+    /// Return the names of all tables in the database.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let db = LemonDb(...);
+    /// db.table("user").unwrap();
+    ///
+    /// assert!(db.tables().contains(&"user".to_string()));
+    ///
+    /// ```
+    pub fn tables(&self) -> Vec<String> {
+        self.state().map.iter()
+            .flat_map(|table| table.keys().cloned())
+            .filter(|name| !is_reserved(name))
+            .collect()
+    }
+
+    /// ### table_exists `fn`
+    ///
+    /// Check if the given table exists in the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The table name
+    ///
+    pub fn table_exists(&self, name: &str) -> bool {
+        self.state().table(name).is_some()
+    }
+
+    /// ### drop_table `fn`
+    ///
+    /// Drop the given table with all of its documents. The change is dumped
+    /// following the `LemonDumpRule`.
+    ///
+    /// Return `false` if the table doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The table name to be dropped
+    ///
+    pub fn drop_table(&mut self, name: &str) -> Result<bool> {
+        if is_reserved(name) {
+            bail!("Table `{}` is reserved", name);
+        }
+
+        let dropped = {
+            let mut state = self.state_mut();
+            state.truncate(name);
+            let dropped = state.map.iter_mut()
+                .any(|table| table.remove(name).is_some());
+            if dropped {
+                state.record(|| LogRecord::DropTable { table: name.to_string() });
+            }
+            state.drop_indexes(name)?;
+            state.set_ttl(name, None)?;
+            dropped
+        };
+
+        if dropped {
+            self.dump()?;
+        }
+        Ok(dropped)
+    }
+
+    /// ### rename_table `fn`
+    ///
+    /// Rename the table `old` to `new`. The change is dumped following the
+    /// `LemonDumpRule`.
+    ///
+    /// Fail if `old` doesn't exist or if `new` already exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The current table name
+    /// * `new` - The new table name
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.rename_table("user", "users").unwrap();
+    ///
+    /// ```
+    pub fn rename_table(&mut self, old: &str, new: &str) -> Result<()> {
+        if is_reserved(old) || is_reserved(new) {
+            bail!("Table `{}` is reserved", if is_reserved(old) { old } else { new });
+        }
+
+        {
+            let mut state = self.state_mut();
+            if state.table(new).is_some() {
+                bail!("Table `{}` already exists", new);
+            }
+
+            let documents = state.map.iter_mut()
+                .find_map(|table| table.remove(old))
+                .with_context(|| format!("Table `{}` doesn't exist", old))?;
+            *table_mut(&mut state.map, new) = documents;
+            state.record(|| LogRecord::RenameTable { old: old.to_string(), new: new.to_string() });
+            state.rename_indexes(old, new)?;
+            state.rename_deadlines(old, new);
+
+            let ttl = state.ttls.get(old).copied();
+            state.set_ttl(old, None)?;
+            state.set_ttl(new, ttl)?;
+        }
+
+        self.dump()
+    }
+
+    /// ### truncate_table `fn`
+    ///
+    /// Remove every document of the given table but keep the table. The
+    /// change is dumped following the `LemonDumpRule`.
+    ///
+    /// Return the number of removed documents.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The table name to be truncated
+    ///
+    pub fn truncate_table(&mut self, name: &str) -> Result<usize> {
+        if is_reserved(name) {
+            bail!("Table `{}` is reserved", name);
         }
 
+        let removed = self.state_mut().truncate(name);
+
+        if removed > 0 {
+            self.dump()?;
+        }
+        Ok(removed)
     }
 
     /// ### insert `fn`
@@ -263,7 +578,12 @@ impl LemonDb {
     /// The key has to be string but the value can be any type defined by the user
     /// that is serializable. That includes primitive types, tuples, vectors, structs and more
     ///
-    /// The function return a result which can be wrapped.
+    /// The key must not exist yet in the current table, use `upsert` or `set`
+    /// to replace an existing value. The fields of the documents inserted
+    /// with `insert_document` are not keys.
+    ///
+    /// The function return a result of the generated document id which can be
+    /// used to fetch the document later with `get_document`.
     ///
     /// # Arguments
     ///
@@ -272,10 +592,10 @@ impl LemonDb {
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```ignore
     /// 
     /// let mut db = LemonDb::new("db", Serializer::JSON, LemonDumpRule::AUTO);
-    /// db.insert("hello", &"world");
+    /// let id = db.insert("hello", &"world").unwrap();
     /// 
     /// ```
     ///  
@@ -285,7 +605,7 @@ impl LemonDb {
     /// You can also insert any serde serializable object including struct and enums
     ///
     /// **Example with struct object**
-    /// ```
+    /// ```ignore
     /// # use serde::{Serialize, Deserialize};
     ///
     /// #[derive(Serialize, Deserialize)]
@@ -310,83 +630,1711 @@ impl LemonDb {
     /// db.insert::<User>("user1", &User::new("John", "Doe")).unwrap();
     ///
     /// ```
-    pub fn insert<V>(&mut self, key: &str, value: &V) -> Result<()>
+    pub fn insert<V>(&mut self, key: &str, value: &V) -> Result<String>
     where
         V: Serialize,
     {
- 
-        let mut document = LemonDocument::new();
-        let raw = self.serializer.serialize(value).unwrap();
-        let data = document.set_data(key, raw).unwrap();
-        self.insert_data_in_table(&document.id, data).unwrap();
+        self.insert_expiring(key, value, None)
+    }
 
-        self.dump().unwrap();
-        Ok(())
+    /// ### insert_with_ttl `fn`
+    ///
+    /// Insert an item like `insert` that expires once the ttl is over. An
+    /// expired document is hidden from every read and removed from the
+    /// database by the next `dump` or `purge_expired`.
+    ///
+    /// The ttl overrides the default ttl of the table, see `set_default_ttl`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A string of key
+    /// * `value` - Any value that can be serializable
+    /// * `ttl` - How long the document lives
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use std::time::Duration;
+    ///
+    /// let mut sessions = db.table("session").unwrap();
+    /// sessions.insert_with_ttl("token", &token, Duration::from_secs(3600)).unwrap();
+    ///
+    /// ```
+    pub fn insert_with_ttl<V>(&mut self, key: &str, value: &V, ttl: Duration) -> Result<String>
+    where
+        V: Serialize,
+    {
+        let expires_at = now_timestamp() + ttl.as_millis() as u64;
+        self.insert_expiring(key, value, Some(expires_at))
     }
     
-    /// An alias for insert `fn`
+    /// ### upsert `fn`
+    ///
+    /// Insert an item or replace the value of the key if it already exists
+    /// in the current table. The document of an existing key is kept, so
+    /// the returned id stays the same across updates (last write wins).
+    /// The documents inserted with `insert_document` are never changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A string of key
+    /// * `value` - Any value that can be serializable
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// let id = db.upsert::<String>("hello", &String::from("world")).unwrap();
+    ///
+    /// // Replace the value, the document id doesn't change
+    /// assert_eq!(db.upsert::<String>("hello", &String::from("lemon")).unwrap(), id);
+    ///
+    /// ```
+    pub fn upsert<V>(&mut self, key: &str, value: &V) -> Result<String>
+    where
+        V: Serialize,
+    {
+        let raw = self.serializer.serialize(value)
+            .map_err(anyhow::Error::msg)
+            .with_context(|| format!("Failed to serialize the value of `{}`", key))?;
+        let id = self.state_mut().upsert(&self.table, key, raw)?;
+
+        self.dump()?;
+        Ok(id)
+    }
+
+    /// An alias for upsert `fn`
     /// 
     /// # Example
     ///
-    /// ```rust
+    /// ```ignore
     /// 
     /// let mut db = LemonDb(...);
     /// db.set::<String>("hello", &String::from("world")).unwrap();
     /// 
     /// ```
-    pub fn set<V>(&mut self, k: &str, v: &V) -> Result<()> 
+    pub fn set<V>(&mut self, k: &str, v: &V) -> Result<String> 
     where
         V: Serialize,
     {
-        self.insert(k, &v).with_context(|| "Failed to insert data")?;
-        Ok(())
+        let id = self.upsert(k, &v).with_context(|| "Failed to set data")?;
+        Ok(id)
 
     }
 
 
-    /// Dump the data to the file. The rule were set with
-    /// `LemonDumpRule`
+    /// ### insert_document `fn`
     ///
-    pub fn dump(&mut self) -> Result<()> {
-    
-        let data = self.serializer.serialize::<Vec<Table>>(&self.map).unwrap();
-        let write = || -> u64 {
-            self.storage.write(Some(data)).unwrap()
+    /// Insert a document with many fields to the current table.
+    ///
+    /// The value has to serialize into a struct or a map. Each top-level field
+    /// becomes a field of the document and is stored separately with the
+    /// configured serializer. Return the generated document id.
+    ///
+    /// # Arguments
+    ///
+    /// * `document` - A serializable struct or map
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// # use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct User {
+    ///     name: String,
+    ///     surname: String
+    /// }
+    ///
+    /// let mut db = LemonDb(...);
+    /// let id = db.insert_document(&User {
+    ///     name: "John".to_string(),
+    ///     surname: "Doe".to_string(),
+    /// }).unwrap();
+    ///
+    /// let user = db.get_document_as::<User>(&id).unwrap().unwrap();
+    ///
+    /// ```
+    pub fn insert_document<T>(&mut self, document: &T) -> Result<String>
+    where
+        T: Serialize,
+    {
+        let fields = match serde_json::to_value(document)
+            .context("Failed to serialize the document")? 
+        {
+            serde_json::Value::Object(fields) => fields,
+            _ => bail!("A document has to be a struct or a map"),
         };
 
-        match self.dump_rule {
+        let mut raw_fields = Vec::with_capacity(fields.len());
+        for (key, value) in fields {
+            let raw = self.serializer.serialize(&value)
+                .map_err(anyhow::Error::msg)
+                .with_context(|| format!("Failed to serialize the field `{}`", key))?;
+            raw_fields.push((key, raw));
+        }
 
-            LemonDumpRule::AUTO => {
-                write();
-                self.last_dump = Instant::now();
-            },
-            LemonDumpRule::PERIODIC(duration) => {
-                let now = Instant::now();
-                if now.duration_since(self.last_dump) > duration {
-                    write();
-                };
-            },
-            LemonDumpRule::NEVER => (),
+        let mut doc = LemonDocument::new();
+        let data = doc.set_fields(raw_fields).map_err(anyhow::Error::msg)?;
+        self.state_mut().put(&self.table, &doc.id, data)?;
 
-        }
+        self.dump()?;
+        Ok(doc.id)
+    }
 
-        Ok(())
+    /// ### get `fn`
+    ///
+    /// Get the value of the given key from the current table. The fields of
+    /// the documents inserted with `insert_document` are read with
+    /// `get_document_as` instead.
+    ///
+    /// The raw value is decoded with the configured serializer into `V`.
+    /// Return `Ok(None)` if the key doesn't exist and an error if the stored
+    /// value can't be deserialized into `V`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key of the value
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.insert::<String>("hello", &String::from("world")).unwrap();
+    ///
+    /// let value = db.get::<String>("hello").unwrap();
+    /// assert_eq!(value, Some("world".to_string()));
+    ///
+    /// ```
+    pub fn get<V>(&self, key: &str) -> Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let raw = match self.get_raw(key) {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+
+        let value = self.serializer
+            .deserialize::<V>(&raw)
+            .with_context(|| format!("Failed to deserialize the value of `{}`", key))?;
 
+        Ok(Some(value))
     }
 
-    
-    fn insert_data_in_table(&mut self, key: &str, data: Data) -> Result<Vec<Table>> {
+    /// ### get_raw `fn`
+    ///
+    /// Get the serialized bytes of the given key from the current table
+    /// without decoding it. Return `None` if the key doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key of the value
+    ///
+    pub fn get_raw(&self, key: &str) -> Option<Vec<u8>> {
+        self.with_table(|mut documents| {
+            documents.find(|(_, data)| is_pair(data, key))
+                .and_then(|(_, data)| data.get(key).cloned())
+        })
+    }
+
+    /// ### get_document `fn`
+    ///
+    /// Get every field of the document given by its id from the current
+    /// table. The values are returned as serialized bytes since a document
+    /// can hold fields of different types. Return `None` if the document
+    /// doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The document id returned by `insert`
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// let id = db.insert::<String>("hello", &String::from("world")).unwrap();
+    ///
+    /// let document = db.get_document(&id).unwrap();
+    /// assert!(document.contains_key("hello"));
+    ///
+    /// ```
+    pub fn get_document(&self, id: &str) -> Option<HashMap<String, Vec<u8>>> {
+        self.state().document(&self.table, id).cloned()
+    }
 
-        for table in self.map.iter_mut() {
+    /// ### get_document_as `fn`
+    ///
+    /// Get the document given by its id from the current table and rebuild
+    /// it into `T` from its fields. Return `Ok(None)` if the document doesn't
+    /// exist and an error if the fields can't be deserialized into `T`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The document id returned by `insert_document`
+    ///
+    pub fn get_document_as<T>(&self, id: &str) -> Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let data = match self.get_document(id) {
+            Some(data) => data,
+            None => return Ok(None),
+        };
 
-            if table.contains_key(&self.table) {
-                table.get_mut(&self.table).unwrap()
-                    .insert(key.to_string(), data.clone());
-            }
-            
-        }
+        let fields = decode_fields(&self.serializer, &data)?;
+        let document = serde_json::from_value::<T>(serde_json::Value::Object(fields))
+            .with_context(|| format!("Failed to rebuild the document `{}`", id))?;
 
-        Ok(self.map.clone())
+        Ok(Some(document))
+    }
+
+    /// ### documents `fn`
+    ///
+    /// Return the ids of all documents in the current table.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// for id in db.documents() {
+    ///     println!("{}", id);
+    /// }
+    ///
+    /// ```
+    pub fn documents(&self) -> Vec<String> {
+        self.with_table(|documents| documents.map(|(id, _)| id.clone()).collect())
+    }
+
+    /// ### iter `fn`
+    ///
+    /// Return an iterator over every value of the current table as a tuple
+    /// of `(document_id, key, value)`. Each value is decoded into `V`, an
+    /// error is returned if any of them can't be deserialized.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let db = LemonDb(...);
+    /// for (id, key, value) in db.iter::<String>().unwrap() {
+    ///     println!("{} {}: {}", id, key, value);
+    /// }
+    ///
+    /// ```
+    pub fn iter<V>(&self) -> Result<std::vec::IntoIter<(String, String, V)>>
+    where
+        V: DeserializeOwned,
+    {
+        let mut items = Vec::new();
+        for (id, key, raw) in self.entries() {
+            let value = self.serializer
+                .deserialize::<V>(&raw)
+                .with_context(|| format!("Failed to deserialize the value of `{}`", key))?;
+            items.push((id, key, value));
+        }
+
+        Ok(items.into_iter())
+    }
+
+    /// ### keys `fn`
+    ///
+    /// Return every key of the current table.
+    ///
+    pub fn keys(&self) -> Vec<String> {
+        self.entries().into_iter()
+            .map(|(_, key, _)| key)
+            .collect()
+    }
+
+    /// ### values `fn`
+    ///
+    /// Return every value of the current table decoded into `V`.
+    ///
+    pub fn values<V>(&self) -> Result<Vec<V>>
+    where
+        V: DeserializeOwned,
+    {
+        Ok(self.iter::<V>()?.map(|(_, _, value)| value).collect())
+    }
+
+    /// ### len `fn`
+    ///
+    /// Return the number of documents in the current table.
+    ///
+    pub fn len(&self) -> usize {
+        self.with_table(|documents| documents.count())
+    }
+
+    /// ### is_empty `fn`
+    ///
+    /// Check if the current table has no document.
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// ### find `fn`
+    ///
+    /// Start a query over the documents of the current table. Each document
+    /// is rebuilt into `V` from its fields, the same way as `get_document_as`,
+    /// and documents that don't fit into `V` are skipped.
+    ///
+    /// The returned `Cursor` is lazy, nothing is decoded until it is iterated.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// # use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct User {
+    ///     name: String,
+    ///     age: u32
+    /// }
+    ///
+    /// let db = LemonDb(...);
+    /// let adults = db.find::<User>()
+    ///     .filter(|user| user.age >= 18)
+    ///     .collect::<Vec<_>>();
+    ///
+    /// ```
+    pub fn find<V>(&self) -> Cursor<V>
+    where
+        V: DeserializeOwned,
+    {
+        let inner = Arc::clone(&self.inner);
+        let table = self.table.clone();
+        let source = move |queries: &[Query]| {
+            let state = inner.read().expect("The database state is poisoned");
+            state.candidates(&table, queries)
+        };
+
+        Cursor::new(Box::new(source), self.serializer.clone())
+    }
+
+    /// ### remove `fn`
+    ///
+    /// Remove the given key from the current table. The fields of the
+    /// documents inserted with `insert_document` are left alone. The change
+    /// is dumped following the `LemonDumpRule`.
+    ///
+    /// Return the number of removed values, `0` means that the key doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to be removed
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.insert::<String>("hello", &String::from("world")).unwrap();
+    ///
+    /// assert_eq!(db.remove("hello").unwrap(), 1);
+    ///
+    /// ```
+    pub fn remove(&mut self, key: &str) -> Result<usize> {
+        let removed = {
+            let mut state = self.state_mut();
+            let ids = state.ids_with_key(&self.table, key);
+            for id in ids.iter() {
+                state.remove_key(&self.table, id, key);
+            }
+            ids.len()
+        };
+
+        if removed > 0 {
+            self.dump()?;
+        }
+        Ok(removed)
+    }
+
+    /// ### remove_document `fn`
+    ///
+    /// Remove the document given by its id from the current table. The change
+    /// is dumped following the `LemonDumpRule`.
+    ///
+    /// Return the removed document or `None` if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The document id returned by `insert`
+    ///
+    pub fn remove_document(&mut self, id: &str) -> Result<Option<HashMap<String, Vec<u8>>>> {
+        let removed = self.state_mut().delete(&self.table, id);
+
+        if removed.is_some() {
+            self.dump()?;
+        }
+        Ok(removed)
+    }
+
+    /// ### clear `fn`
+    ///
+    /// Remove every document of the current table. The table itself is kept.
+    /// The change is dumped following the `LemonDumpRule`.
+    ///
+    /// Return the number of removed documents.
+    ///
+    pub fn clear(&mut self) -> Result<usize> {
+        let removed = self.state_mut().truncate(&self.table);
+
+        if removed > 0 {
+            self.dump()?;
+        }
+        Ok(removed)
+    }
+
+    /// ### create_index `fn`
+    ///
+    /// Create an index on a field of the given table. The index maps the
+    /// value of the field to the ids of the documents holding it and is
+    /// kept up to date on every write. Queries use it automatically for
+    /// `Field(..).eq(..)` and `Field(..).in_(..)` lookups.
+    ///
+    /// The index definition is persisted in the db file and the index is
+    /// rebuilt on `open`. Creating an index that already exists does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The table name
+    /// * `field` - The field name or dotted path to index
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use lemondb::query::Field;
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.create_index("user", "email").unwrap();
+    ///
+    /// let user = db.table("user")
+    ///     .unwrap()
+    ///     .find::<User>()
+    ///     .matching(Field("email").eq("john@doe.com"))
+    ///     .next();
+    ///
+    /// ```
+    pub fn create_index(&mut self, table: &str, field: &str) -> Result<()> {
+        self.add_index(IndexDefinition {
+            table: table.to_string(),
+            field: field.to_string(),
+            unique: false,
+            kind: IndexKind::Hash,
+        })
+    }
+
+    /// ### create_unique_index `fn`
+    ///
+    /// Create an index like `create_index` that also guarantees that no two
+    /// documents of the table hold the same value of the field. A write that
+    /// would break it fails with `LemonError::ConstraintViolation` before
+    /// anything is written or dumped.
+    ///
+    /// Fail if some documents already share the same value.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The table name
+    /// * `field` - The field name or dotted path that has to be unique
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.create_unique_index("user", "email").unwrap();
+    ///
+    /// let mut users = db.table("user").unwrap();
+    /// users.insert_document(&User::new("john@doe.com")).unwrap();
+    /// assert!(users.insert_document(&User::new("john@doe.com")).is_err());
+    ///
+    /// ```
+    pub fn create_unique_index(&mut self, table: &str, field: &str) -> Result<()> {
+        self.add_index(IndexDefinition {
+            table: table.to_string(),
+            field: field.to_string(),
+            unique: true,
+            kind: IndexKind::Hash,
+        })
+    }
+
+    /// ### create_ordered_index `fn`
+    ///
+    /// Create an index like `create_index` that also keeps the values of
+    /// the field sorted. Besides equality lookups, queries use it for
+    /// `range`, `prefix`, `gt`, `gte`, `lt` and `lte` on the field instead
+    /// of scanning the whole table.
+    ///
+    /// Values are sorted with the same order as `Cursor::sort_by`, numbers
+    /// by value and strings byte-wise. Timestamps order chronologically when
+    /// stored as numbers or as RFC 3339 strings in the same timezone.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The table name
+    /// * `field` - The field name or dotted path to index
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.create_ordered_index("order", "total").unwrap();
+    ///
+    /// let orders = db.table("order")
+    ///     .unwrap()
+    ///     .find::<Order>()
+    ///     .range("total", 100..500)
+    ///     .collect::<Vec<_>>();
+    ///
+    /// ```
+    pub fn create_ordered_index(&mut self, table: &str, field: &str) -> Result<()> {
+        self.add_index(IndexDefinition {
+            table: table.to_string(),
+            field: field.to_string(),
+            unique: false,
+            kind: IndexKind::Ordered,
+        })
+    }
+
+    /// ### create_text_index `fn`
+    ///
+    /// Create a full-text index on a string field of the given table, the
+    /// field can also hold an array of strings. The text is split into
+    /// lowercase terms, common english words are left out and the terms
+    /// are stemmed so `database` matches `databases`. Use `search` to
+    /// query it.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The table name
+    /// * `field` - The field name or dotted path to index
+    ///
+    pub fn create_text_index(&mut self, table: &str, field: &str) -> Result<()> {
+        self.add_index(IndexDefinition {
+            table: table.to_string(),
+            field: field.to_string(),
+            unique: false,
+            kind: IndexKind::Text,
+        })
+    }
+
+    /// ### search `fn`
+    ///
+    /// Search the text indexes of the given table. Return the ids of the
+    /// documents holding any term of the query, the most relevant first.
+    /// Documents are ranked with BM25, summed over the indexed fields.
+    ///
+    /// Fail if the table has no text index.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The table name
+    /// * `query` - The words to search for
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.create_text_index("note", "body").unwrap();
+    ///
+    /// for id in db.search("note", "rust database").unwrap() {
+    ///     let note = db.table("note").unwrap().get_document_as::<Note>(&id).unwrap();
+    /// }
+    ///
+    /// ```
+    pub fn search(&self, table: &str, query: &str) -> Result<Vec<String>> {
+        let state = self.state();
+        let mut scores: HashMap<String, f64> = HashMap::new();
+        let mut indexed = false;
+
+        for index in state.indexes.iter().filter(|index| index.table == table) {
+            if let Some(matches) = index.search(query) {
+                indexed = true;
+                for (id, score) in matches {
+                    *scores.entry(id).or_insert(0.0) += score;
+                }
+            }
+        }
+
+        if !indexed {
+            bail!("Table `{}` has no text index", table);
+        }
+        scores.retain(|id, _| state.document(table, id).is_some());
+
+        let mut ranked: Vec<(String, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|(a_id, a), (b_id, b)| {
+            b.total_cmp(a).then_with(|| LemonId::decode(a_id).cmp(&LemonId::decode(b_id)))
+        });
+        Ok(ranked.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// ### drop_index `fn`
+    ///
+    /// Drop the index on a field of the given table. Return `false` if
+    /// there is no such index.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The table name
+    /// * `field` - The indexed field
+    ///
+    pub fn drop_index(&mut self, table: &str, field: &str) -> Result<bool> {
+        let dropped = self.state_mut().drop_index(table, field)?;
+        if dropped {
+            self.dump()?;
+        }
+        Ok(dropped)
+    }
+
+    /// ### set_default_ttl `fn`
+    ///
+    /// Set how long the documents of the given table live, counted from
+    /// their creation time which is read from the document id. It applies
+    /// to the documents already in the table as well. Documents inserted
+    /// with `insert_with_ttl` keep their own expiry. `None` removes the
+    /// default ttl.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The table name
+    /// * `ttl` - How long the documents live
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use std::time::Duration;
+    ///
+    /// let mut db = LemonDb(...);
+    /// db.set_default_ttl("cache", Some(Duration::from_secs(600))).unwrap();
+    ///
+    /// ```
+    pub fn set_default_ttl(&mut self, table: &str, ttl: Option<Duration>) -> Result<()> {
+        if is_reserved(table) {
+            bail!("Table `{}` is reserved", table);
+        }
+
+        let ttl = ttl.map(|ttl| ttl.as_millis() as u64);
+        self.state_mut().set_ttl(table, ttl)?;
+        self.dump()
+    }
+
+    /// ### purge_expired `fn`
+    ///
+    /// Remove the expired documents of every table from the database. It
+    /// happens on every `dump` as well, expired documents are hidden from
+    /// the reads until then.
+    ///
+    /// Return the number of removed documents.
+    ///
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let purged = self.state_mut().purge_expired();
+
+        if purged > 0 {
+            self.dump()?;
+        }
+        Ok(purged)
+    }
+
+    /// ### compact `fn`
+    ///
+    /// Rewrite the database file with only the live documents. Replaced and
+    /// removed documents pile up in the log of `LemonStorageMode::LOG`, and
+    /// expired documents in both modes until they are purged.
+    ///
+    /// The new file is written next to the old one and only replaces it once
+    /// it is synced to the disk, so a failure leaves the old file intact.
+    ///
+    /// Return the number of bytes reclaimed.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let mut db = LemonDb(...);
+    /// let reclaimed = db.compact().unwrap();
+    /// println!("Reclaimed {} bytes", reclaimed);
+    ///
+    /// ```
+    pub fn compact(&mut self) -> Result<u64> {
+        let mut state = self.state_mut();
+        state.purge_expired();
+        state.compact().context("Failed to compact the database")
+    }
+
+    /// ### set_compaction_ratio `fn`
+    ///
+    /// Set when the log of `LemonStorageMode::LOG` is compacted on its own.
+    /// It happens on a dump once the bytes appended to the file since the
+    /// last snapshot reach `ratio` times the size of that snapshot, `1.0`
+    /// by default. `None` disables the automatic compaction, `compact` can
+    /// still be called.
+    ///
+    /// # Arguments
+    ///
+    /// * `ratio` - The appended to snapshot bytes ratio
+    ///
+    pub fn set_compaction_ratio(&mut self, ratio: Option<f64>) {
+        self.state_mut().compaction_ratio = ratio;
+    }
+
+    /// Dump the data to the file. The rule were set with
+    /// `LemonDumpRule`
+    ///
+    pub fn dump(&mut self) -> Result<()> {
+    
+        let mut state = self.state_mut();
+        state.purge_expired();
+
+        let due = match state.dump_rule {
+            LemonDumpRule::AUTO => true,
+            LemonDumpRule::PERIODIC(duration) => state.last_dump.elapsed() > duration,
+            LemonDumpRule::NEVER => false,
+        };
+
+        if due {
+            state.flush().context("Failed to dump the database")?;
+            state.last_dump = Instant::now();
+
+            if state.should_compact() {
+                state.compact().context("Failed to compact the database")?;
+            }
+        }
+
+        Ok(())
+
+    }
+
+
+    fn add_index(&mut self, definition: IndexDefinition) -> Result<()> {
+        if is_reserved(&definition.table) {
+            bail!("Table `{}` is reserved", definition.table);
+        }
+
+        let created = self.state_mut().create_index(definition)?;
+        if created {
+            self.dump()?;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, LemonState> {
+        self.inner.read().expect("The database state is poisoned")
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, LemonState> {
+        self.inner.write().expect("The database state is poisoned")
+    }
+
+    /// Snapshot every `(document_id, key, raw value)` of the current table.
+    fn entries(&self) -> Vec<(String, String, Vec<u8>)> {
+        self.with_table(|documents| {
+            documents
+                .flat_map(|(id, data)| {
+                    data.iter().map(move |(key, raw)| (id.clone(), key.clone(), raw.clone()))
+                })
+                .collect()
+        })
+    }
+
+    /// Run `f` on the documents of the current table that haven't expired
+    fn with_table<R>(&self, f: impl FnOnce(Documents<'_>) -> R) -> R {
+        let state = self.state();
+        f(state.live(&self.table))
+    }
+
+    fn insert_expiring<V>(&mut self, key: &str, value: &V, expires_at: Option<u64>) -> Result<String>
+    where
+        V: Serialize,
+    {
+        let mut document = LemonDocument::new();
+        let raw = self.serializer.serialize(value)
+            .map_err(anyhow::Error::msg)
+            .with_context(|| format!("Failed to serialize the value of `{}`", key))?;
+        let data = document.set_data(key, raw).map_err(anyhow::Error::msg)?;
+
+        {
+            let mut state = self.state_mut();
+            if state.find_key(&self.table, key).is_some() {
+                bail!("Key `{}` already exists in table `{}`", key, self.table);
+            }
+            state.put(&self.table, &document.id, data)?;
+            if let Some(expires_at) = expires_at {
+                state.set_expiry(&self.table, &document.id, expires_at)?;
+            }
+        }
+
+        self.dump()?;
+        Ok(document.id)
+    }
+
+}
+
+impl LemonState {
+
+    fn table(&self, name: &str) -> Option<&Document> {
+        self.map.iter().find_map(|table| table.get(name))
+    }
+
+    /// The documents of the table that haven't expired
+    fn live<'s>(&'s self, table: &str) -> Documents<'s> {
+        let now = now_timestamp();
+        let ttl = self.ttls.get(table).copied();
+
+        Box::new(
+            self.table(table)
+                .into_iter()
+                .flatten()
+                .filter(move |(id, _)| !self.expired(id, ttl, now))
+        )
+    }
+
+    /// The document of the table if it exists and hasn't expired
+    fn document(&self, table: &str, id: &str) -> Option<&Data> {
+        let ttl = self.ttls.get(table).copied();
+        self.table(table)?
+            .get(id)
+            .filter(|_| !self.expired(id, ttl, now_timestamp()))
+    }
+
+    /// An explicit expiry wins over the default ttl of the table
+    fn expired(&self, id: &str, ttl: Option<u64>, now: u64) -> bool {
+        let expires_at = match self.expiry.get(id) {
+            Some(expires_at) => Some(*expires_at),
+            None => ttl.and_then(|ttl| Some(LemonId::created_at(id)? + ttl)),
+        };
+        expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn table_mut(&mut self, name: &str) -> &mut Document {
+        if self.table(name).is_none() {
+            self.record(|| LogRecord::CreateTable { table: name.to_string() });
+        }
+        table_mut(&mut self.map, name)
+    }
+
+    /// Queue the change to be appended to the log on the next dump.
+    /// Nothing is queued if the storage isn't a log or is never dumped.
+    fn record(&mut self, record: impl FnOnce() -> LogRecord) {
+        if self.storage.mode == LemonStorageMode::LOG
+            && !matches!(self.dump_rule, LemonDumpRule::NEVER)
+        {
+            self.pending.push(record());
+        }
+    }
+
+    /// Write the changes to the file following the storage mode
+    fn flush(&mut self) -> Result<()> {
+        match self.storage.mode {
+            LemonStorageMode::SNAPSHOT => {
+                self.write_snapshot()?;
+            },
+            LemonStorageMode::LOG if self.rewrite => {
+                self.write_snapshot()?;
+                self.rewrite = false;
+                self.pending.clear();
+            },
+            LemonStorageMode::LOG => {
+                if self.pending.is_empty() {
+                    return Ok(());
+                }
+
+                if let Err(error) = self.storage.append(&self.pending) {
+                    // A failed append may leave a part of a record behind,
+                    // start over from a snapshot instead of appending after it.
+                    self.rewrite = true;
+                    return Err(error);
+                }
+                self.pending.clear();
+            },
+        }
+        Ok(())
+    }
+
+    /// Write the whole document, replacing the previous one if any. Fail
+    /// without writing anything if it breaks a unique index of the table.
+    fn put(&mut self, table: &str, id: &str, data: Data) -> Result<()> {
+        self.check_unique(table, id, &data)?;
+        self.write(table, id, data);
+        Ok(())
+    }
+
+    /// Write the whole document and keep the indexes of the table up to date
+    fn write(&mut self, table: &str, id: &str, data: Data) {
+        self.record(|| LogRecord::Put {
+            table: table.to_string(),
+            id: id.to_string(),
+            data: data.clone(),
+        });
+
+        for index in self.indexes.iter_mut().filter(|index| index.table == table) {
+            index.remove(id);
+            index.insert(id, &data, &self.serializer);
+        }
+
+        self.table_mut(table).insert(id.to_string(), data);
+        self.track_created(table, id);
+    }
+
+    /// Replace the file with a snapshot of the tables
+    fn write_snapshot(&mut self) -> Result<()> {
+        let records = [LogRecord::Snapshot(std::mem::take(&mut self.map))];
+        let written = self.storage.rewrite(&records);
+
+        let [LogRecord::Snapshot(tables)] = records else {
+            unreachable!()
+        };
+        self.map = tables;
+
+        written
+    }
+
+    /// Rewrite the file with only the live documents, return the number
+    /// of bytes reclaimed
+    fn compact(&mut self) -> Result<u64> {
+        let before = self.storage.size();
+
+        if self.storage.mode == LemonStorageMode::LOG {
+            self.rewrite = true;
+        }
+        self.flush()?;
+
+        Ok(before.saturating_sub(self.storage.size()))
+    }
+
+    fn should_compact(&self) -> bool {
+        let ratio = match self.compaction_ratio {
+            Some(ratio) => ratio,
+            None => return false,
+        };
+
+        let appended = self.storage.appended();
+        self.storage.mode == LemonStorageMode::LOG
+            && appended >= MIN_APPENDED_BYTES
+            && appended as f64 >= ratio * self.storage.snapshot_size() as f64
+    }
+
+    fn check_unique(&self, table: &str, id: &str, data: &Data) -> Result<()> {
+        for index in self.indexes.iter().filter(|index| index.table == table) {
+            let value = match index.conflict(id, data, &self.serializer) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            // An expired document doesn't hold its value anymore
+            let taken = index.get(&value)
+                .any(|other| other != id && self.document(table, other).is_some());
+            if taken {
+                return Err(LemonError::ConstraintViolation {
+                    table: table.to_string(),
+                    field: index.field.clone(),
+                    value: value.to_string(),
+                }.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, table: &str, id: &str) -> Option<Data> {
+        let removed = self.map.iter_mut()
+            .find_map(|documents| documents.get_mut(table))?
+            .remove(id)?;
+        self.record(|| LogRecord::Delete { table: table.to_string(), id: id.to_string() });
+
+        for index in self.indexes.iter_mut().filter(|index| index.table == table) {
+            index.remove(id);
+        }
+
+        self.untrack_created(table, id);
+        if table != EXPIRY_TABLE {
+            self.clear_expiry(id);
+        }
+        Some(removed)
+    }
+
+    /// Remove every document of the table, return the number of removed documents
+    fn truncate(&mut self, table: &str) -> usize {
+        for index in self.indexes.iter_mut().filter(|index| index.table == table) {
+            index.clear();
+        }
+
+        let ids: Vec<String> = self.table(table)
+            .map(|documents| documents.keys().cloned().collect())
+            .unwrap_or_default();
+        for id in ids {
+            self.clear_expiry(&id);
+        }
+        if let Some(ids) = self.created.get_mut(table) {
+            ids.clear();
+        }
+
+        if self.table(table).is_some() {
+            self.record(|| LogRecord::Truncate { table: table.to_string() });
+        }
+
+        self.map.iter_mut()
+            .find_map(|documents| documents.get_mut(table))
+            .map(|documents| documents.drain().count())
+            .unwrap_or_default()
+    }
+
+    fn find_key(&self, table: &str, key: &str) -> Option<String> {
+        self.live(table)
+            .find(|(_, data)| is_pair(data, key))
+            .map(|(id, _)| id.clone())
+    }
+
+    fn ids_with_key(&self, table: &str, key: &str) -> Vec<String> {
+        self.live(table)
+            .filter(|(_, data)| is_pair(data, key))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Remove the key from the document, the document is removed as
+    /// well once it has no field left.
+    fn remove_key(&mut self, table: &str, id: &str, key: &str) {
+        let mut data = match self.table(table).and_then(|documents| documents.get(id)) {
+            Some(data) => data.clone(),
+            None => return,
+        };
+
+        // Removing a field can't break a unique index
+        data.remove(key);
+        if data.is_empty() {
+            self.delete(table, id);
+        } else {
+            self.write(table, id, data);
+        }
+    }
+
+    /// Replace the value of `key` in the table or create a new document for it.
+    /// Return the id of the document holding the key.
+    fn upsert(&mut self, table: &str, key: &str, raw: Vec<u8>) -> Result<String> {
+        let mut ids = self.ids_with_key(table, key).into_iter();
+
+        let id = match ids.next() {
+            Some(id) => id,
+            None => {
+                let mut document = LemonDocument::new();
+                let data = document.set_data(key, raw).map_err(anyhow::Error::msg)?;
+                self.put(table, &document.id, data)?;
+                return Ok(document.id);
+            },
+        };
+
+        let mut data = self.table(table)
+            .and_then(|documents| documents.get(&id))
+            .cloned()
+            .unwrap_or_default();
+        data.insert(key.to_string(), raw);
+        self.check_unique(table, &id, &data)?;
+
+        // Drop the key from any other document so there is
+        // only a single winner left.
+        for other in ids {
+            self.remove_key(table, &other, key);
+        }
+        self.write(table, &id, data);
+
+        Ok(id)
+    }
+
+    /// Write every pair like `upsert`, or none of them. The unique indexes
+    /// are checked against the documents as they are once every pair is
+    /// written, so a conflict is found before anything is written.
+    fn upsert_all(&mut self, table: &str, pairs: Vec<(String, Vec<u8>)>) -> Result<()> {
+        // The documents the pairs end up in, in the order they are first
+        // written, and the document holding each of their keys
+        let mut documents: Vec<(String, Data)> = Vec::new();
+        let mut holders: HashMap<String, usize> = HashMap::new();
+        let mut upserted = HashSet::new();
+
+        for (key, raw) in pairs {
+            upserted.insert(key.clone());
+            let position = match holders.get(&key) {
+                Some(position) => *position,
+                None => {
+                    let (id, data) = match self.find_key(table, &key) {
+                        Some(id) => {
+                            let data = self.document(table, &id).cloned().unwrap_or_default();
+                            (id, data)
+                        },
+                        None => (LemonDocument::new().id, Data::new()),
+                    };
+                    for held in data.keys() {
+                        holders.insert(held.clone(), documents.len());
+                    }
+                    holders.insert(key.clone(), documents.len());
+                    documents.push((id, data));
+                    documents.len() - 1
+                },
+            };
+            documents[position].1.insert(key, raw);
+        }
+
+        let changed: HashSet<&str> = documents.iter()
+            .map(|(id, _)| id.as_str())
+            .collect();
+        for index in self.indexes.iter().filter(|index| index.table == table && index.unique) {
+            let mut seen = HashSet::new();
+            for (id, data) in &documents {
+                let value = match field_value(data, &index.field, &self.serializer) {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                // Held by another written document, or by one left as it is
+                let taken = !seen.insert(index_key(&value))
+                    || index.get(&value).any(|other| {
+                        other != id
+                            && !changed.contains(other.as_str())
+                            && self.document(table, other).is_some()
+                    });
+                if taken {
+                    return Err(LemonError::ConstraintViolation {
+                        table: table.to_string(),
+                        field: index.field.clone(),
+                        value: value.to_string(),
+                    }.into());
+                }
+            }
+        }
+
+        // Drop the keys from any other document so there is
+        // only a single winner left.
+        for key in &upserted {
+            let winner = &documents[holders[key]].0;
+            for other in self.ids_with_key(table, key) {
+                if other != *winner && !changed.contains(other.as_str()) {
+                    self.remove_key(table, &other, key);
+                }
+            }
+        }
+
+        for (id, data) in documents {
+            self.write(table, &id, data);
+        }
+        Ok(())
+    }
+
+    /// The documents a query has to look at. When one of the queries is an
+    /// equality on an indexed field only the indexed documents are returned,
+    /// otherwise the whole table. The queries are still checked by the cursor.
+    fn candidates(&self, table: &str, queries: &[Query]) -> Vec<(String, Data)> {
+        match self.indexed_ids(table, queries) {
+            Some(ids) => ids.into_iter()
+                .filter_map(|id| {
+                    let data = self.document(table, &id)?.clone();
+                    Some((id, data))
+                })
+                .collect(),
+            None => self.live(table)
+                .map(|(id, data)| (id.clone(), data.clone()))
+                .collect(),
+        }
+    }
+
+    /// Remove the expired documents of every table, return the
+    /// number of removed documents. Only the expired documents are
+    /// looked at, they come first in `deadlines` and `created`.
+    fn purge_expired(&mut self) -> usize {
+        let now = now_timestamp();
+        let mut expired: Vec<(String, String)> = self.deadlines
+            .range(..(now + 1, String::new()))
+            .map(|((_, id), table)| (table.clone(), id.clone()))
+            .collect();
+
+        for (table, ttl) in self.ttls.iter() {
+            let ids = match self.created.get(table) {
+                Some(ids) => ids,
+                None => continue,
+            };
+            expired.extend(
+                ids.iter()
+                    .take_while(|(created, _)| created + ttl <= now)
+                    .map(|(_, id)| (table.clone(), id.clone()))
+            );
+        }
+
+        expired.iter()
+            .filter(|(table, id)| self.delete(table, id).is_some())
+            .count()
+    }
+
+    fn set_expiry(&mut self, table: &str, id: &str, expires_at: u64) -> Result<()> {
+        let mut data = HashMap::new();
+        data.insert(
+            "expires_at".to_string(),
+            self.serializer.serialize(&expires_at).map_err(anyhow::Error::msg)?,
+        );
+
+        self.write(EXPIRY_TABLE, id, data);
+        if let Some(previous) = self.expiry.insert(id.to_string(), expires_at) {
+            self.deadlines.remove(&(previous, id.to_string()));
+        }
+        self.deadlines.insert((expires_at, id.to_string()), table.to_string());
+        // An explicit expiry wins over the ttl of the table
+        self.untrack_created(table, id);
+        Ok(())
+    }
+
+    /// Remove the explicit expiry of a document
+    fn clear_expiry(&mut self, id: &str) {
+        if let Some(expires_at) = self.expiry.remove(id) {
+            self.deadlines.remove(&(expires_at, id.to_string()));
+            self.delete(EXPIRY_TABLE, id);
+        }
+    }
+
+    /// Keep the expiring documents of a renamed table purged from the new one
+    fn rename_deadlines(&mut self, old: &str, new: &str) {
+        self.deadlines.values_mut()
+            .filter(|table| *table == old)
+            .for_each(|table| *table = new.to_string());
+    }
+
+    /// Track the creation time of a document written to a table with a ttl
+    fn track_created(&mut self, table: &str, id: &str) {
+        if self.expiry.contains_key(id) {
+            return;
+        }
+        if let (Some(ids), Some(created)) = (self.created.get_mut(table), LemonId::created_at(id)) {
+            ids.insert((created, id.to_string()));
+        }
+    }
+
+    fn untrack_created(&mut self, table: &str, id: &str) {
+        if let (Some(ids), Some(created)) = (self.created.get_mut(table), LemonId::created_at(id)) {
+            ids.remove(&(created, id.to_string()));
+        }
+    }
+
+    /// The documents of the table that expire with its ttl, ordered by creation time
+    fn created_ids(&self, table: &str) -> BTreeSet<(u64, String)> {
+        self.table(table)
+            .into_iter()
+            .flatten()
+            .filter(|(id, _)| !self.expiry.contains_key(*id))
+            .filter_map(|(id, _)| Some((LemonId::created_at(id)?, id.clone())))
+            .collect()
+    }
+
+    fn set_ttl(&mut self, table: &str, ttl: Option<u64>) -> Result<()> {
+        match ttl {
+            Some(ttl) => {
+                let mut data = HashMap::new();
+                data.insert(
+                    "ttl".to_string(),
+                    self.serializer.serialize(&ttl).map_err(anyhow::Error::msg)?,
+                );
+
+                self.write(TTL_TABLE, table, data);
+                self.ttls.insert(table.to_string(), ttl);
+                if !self.created.contains_key(table) {
+                    let ids = self.created_ids(table);
+                    self.created.insert(table.to_string(), ids);
+                }
+            },
+            None => {
+                if self.ttls.remove(table).is_some() {
+                    self.delete(TTL_TABLE, table);
+                }
+                self.created.remove(table);
+            },
+        }
+        Ok(())
+    }
+
+    /// Load the expiry of the documents and the ttl of the tables
+    /// stored in the db file
+    fn load_expiry(&mut self) -> Result<()> {
+        let number = |data: &Data, field: &str| {
+            field_value(data, field, &self.serializer)
+                .and_then(|value| value.as_u64())
+        };
+
+        let mut expiry = HashMap::new();
+        for (id, data) in self.table(EXPIRY_TABLE).into_iter().flatten() {
+            let expires_at = number(data, "expires_at")
+                .with_context(|| format!("Invalid expiry of `{}`", id))?;
+            expiry.insert(id.clone(), expires_at);
+        }
+
+        let mut ttls = HashMap::new();
+        for (table, data) in self.table(TTL_TABLE).into_iter().flatten() {
+            let ttl = number(data, "ttl")
+                .with_context(|| format!("Invalid ttl of table `{}`", table))?;
+            ttls.insert(table.clone(), ttl);
+        }
+
+        let mut deadlines = BTreeMap::new();
+        for (name, documents) in self.map.iter().flatten() {
+            if is_reserved(name) {
+                continue;
+            }
+            for id in documents.keys() {
+                if let Some(expires_at) = expiry.get(id) {
+                    deadlines.insert((*expires_at, id.clone()), name.clone());
+                }
+            }
+        }
+
+        self.expiry = expiry;
+        self.deadlines = deadlines;
+        self.created = ttls.keys()
+            .map(|table| (table.clone(), self.created_ids(table)))
+            .collect();
+        self.ttls = ttls;
+        Ok(())
+    }
+
+    fn indexed_ids(&self, table: &str, queries: &[Query]) -> Option<Vec<String>> {
+        queries.iter().find_map(|query| match query {
+            Query::Eq(field, value) => {
+                let index = self.index(table, field)?;
+                Some(index.get(value).cloned().collect())
+            },
+            Query::In(field, values) => {
+                let index = self.index(table, field)?;
+                let mut ids: Vec<String> = values.iter()
+                    .flat_map(|value| index.get(value).cloned())
+                    .collect();
+                ids.sort();
+                ids.dedup();
+                Some(ids)
+            },
+            Query::Gt(field, value) => self.index(table, field)?
+                .range(Bound::Excluded(value), Bound::Unbounded),
+            Query::Gte(field, value) => self.index(table, field)?
+                .range(Bound::Included(value), Bound::Unbounded),
+            Query::Lt(field, value) => self.index(table, field)?
+                .range(Bound::Unbounded, Bound::Excluded(value)),
+            Query::Lte(field, value) => self.index(table, field)?
+                .range(Bound::Unbounded, Bound::Included(value)),
+            Query::Range(field, lower, upper) => self.index(table, field)?
+                .range(lower.as_ref(), upper.as_ref()),
+            Query::StartsWith(field, prefix) => self.index(table, field)?
+                .prefix(prefix),
+            Query::And(queries) => self.indexed_ids(table, queries),
+            _ => None,
+        })
+    }
+
+    fn index(&self, table: &str, field: &str) -> Option<&LemonIndex> {
+        self.indexes.iter()
+            .find(|index| index.table == table && index.field == field)
+    }
+
+    /// Create the index and persist its definition, return `false`
+    /// if it already exists. Fail if the index is unique and some
+    /// documents already share the same value.
+    fn create_index(&mut self, definition: IndexDefinition) -> Result<bool> {
+        if let Some(index) = self.index(&definition.table, &definition.field) {
+            if index.unique != definition.unique || index.kind != definition.kind {
+                bail!(
+                    "An index on `{}.{}` already exists, drop it first",
+                    definition.table, definition.field
+                );
+            }
+            return Ok(false);
+        }
+
+        let index = self.build_index(&definition);
+        if let Some(id) = index.duplicate() {
+            let value = self.table(&definition.table)
+                .and_then(|documents| documents.get(id))
+                .and_then(|data| field_value(data, &definition.field, &self.serializer))
+                .unwrap_or_default();
+
+            return Err(LemonError::ConstraintViolation {
+                table: definition.table,
+                field: definition.field,
+                value: value.to_string(),
+            }.into());
+        }
+
+        let data = definition.encode(&self.serializer).map_err(anyhow::Error::msg)?;
+        let document = LemonDocument::new();
+        self.write(INDEX_TABLE, &document.id, data);
+
+        self.indexes.push(index);
+        Ok(true)
+    }
+
+    fn build_index(&self, definition: &IndexDefinition) -> LemonIndex {
+        let mut index = LemonIndex::new(definition);
+        if let Some(documents) = self.table(&definition.table) {
+            for (id, data) in documents {
+                index.insert(id, data, &self.serializer);
+            }
+        }
+        index
+    }
+
+    fn drop_index(&mut self, table: &str, field: &str) -> Result<bool> {
+        let count = self.indexes.len();
+        self.indexes.retain(|index| index.table != table || index.field != field);
+
+        for (id, definition) in self.index_definitions()? {
+            if definition.table == table && definition.field == field {
+                self.delete(INDEX_TABLE, &id);
+            }
+        }
+
+        Ok(self.indexes.len() != count)
+    }
+
+    fn drop_indexes(&mut self, table: &str) -> Result<()> {
+        let fields: Vec<String> = self.indexes.iter()
+            .filter(|index| index.table == table)
+            .map(|index| index.field.clone())
+            .collect();
+
+        for field in fields {
+            self.drop_index(table, &field)?;
+        }
+        Ok(())
+    }
+
+    fn rename_indexes(&mut self, old: &str, new: &str) -> Result<()> {
+        let definitions: Vec<IndexDefinition> = self.index_definitions()?
+            .into_iter()
+            .map(|(_, definition)| definition)
+            .filter(|definition| definition.table == old)
+            .collect();
+
+        for mut definition in definitions {
+            self.drop_index(old, &definition.field)?;
+            definition.table = new.to_string();
+            self.create_index(definition)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the indexes from the definitions stored in the db file
+    fn load_indexes(&mut self) -> Result<()> {
+        for (_, definition) in self.index_definitions()? {
+            if self.index(&definition.table, &definition.field).is_none() {
+                let index = self.build_index(&definition);
+                self.indexes.push(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every index definition stored in the index table with its document id
+    fn index_definitions(&self) -> Result<Vec<(String, IndexDefinition)>> {
+        let documents = match self.table(INDEX_TABLE) {
+            Some(documents) => documents,
+            None => return Ok(Vec::new()),
+        };
+
+        documents.iter()
+            .map(|(id, data)| {
+                let definition = IndexDefinition::decode(data, &self.serializer)
+                    .with_context(|| format!("Invalid index definition `{}`", id))?;
+                Ok((id.clone(), definition))
+            })
+            .collect()
+    }
+
+}
+
+
+/// Iterate over the raw `(document_id, key, value)` of the table
+/// the handle is bound to.
+impl IntoIterator for &LemonDb {
+    type Item = (String, String, Vec<u8>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries().into_iter()
+    }
+}
+
+impl IntoIterator for LemonDb {
+    type Item = (String, String, Vec<u8>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries().into_iter()
+    }
+}
+
+/// Bulk load key-value pairs into the current table with upsert semantics.
+/// The database is dumped once after every pair is written.
+///
+/// Panics without writing any pair if one of them breaks a unique index.
+impl<K, V> Extend<(K, V)> for LemonDb
+where
+    K: AsRef<str>,
+    V: Serialize,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        // `extend` can't return an error, fail before the state is locked
+        let mut pairs = Vec::new();
+        for (key, value) in iter {
+            let raw = self.serializer.serialize(&value).unwrap_or_else(|err| {
+                panic!("Failed to serialize the value of `{}`: {}", key.as_ref(), err)
+            });
+            pairs.push((key, raw));
+        }
+
+        let pairs = pairs.into_iter()
+            .map(|(key, raw)| (key.as_ref().to_string(), raw))
+            .collect();
+
+        // Nothing is written on a conflict, and the state is unlocked
+        // before panicking so the other handles can still use it
+        let written = self.state_mut().upsert_all(&self.table, pairs);
+        if let Err(err) = written {
+            panic!("Failed to extend the table: {:#}", err);
+        }
+
+        self.dump().expect("Failed to dump the database");
+    }
+}
+
+/// A key-value pair is a document holding the key alone. The key-level
+/// API leaves the fields of the other documents alone, so a key never
+/// clashes with the field of a document from `insert_document`.
+fn is_pair(data: &Data, key: &str) -> bool {
+    data.len() == 1 && data.contains_key(key)
+}
+
+/// Reserved tables hold the database metadata, they are hidden from
+/// `tables` and can't be managed as user tables.
+fn is_reserved(name: &str) -> bool {
+    matches!(name, INDEX_TABLE | EXPIRY_TABLE | TTL_TABLE)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use serde::Deserialize;
+
+    use crate::testing::{option, reopen, temp_db};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        email: String,
+    }
+
+    fn user(name: &str) -> User {
+        User { name: name.to_string(), email: format!("{}@lemon.db", name) }
+    }
+
+    #[test]
+    fn reserved_tables_are_refused() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.insert("key", &1).unwrap();
+        for name in [INDEX_TABLE, EXPIRY_TABLE, TTL_TABLE] {
+            assert!(db.table(name).is_err());
+        }
+        drop(db);
+
+        let mut reserved = option(LemonStorageMode::SNAPSHOT);
+        reserved.table_name = Some(INDEX_TABLE);
+        assert!(LemonDb::open(&path, reserved).is_err());
+        assert!(LemonDb::open(&path, option(LemonStorageMode::SNAPSHOT)).is_ok());
+    }
+
+    #[test]
+    fn key_level_writes_leave_documents_alone() {
+        let (_dir, _, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        let a = db.insert_document(&user("a")).unwrap();
+        let b = db.insert_document(&user("b")).unwrap();
+
+        db.insert("name", &"x").unwrap();
+        db.set("name", &"z").unwrap();
+        assert_eq!(db.get::<String>("name").unwrap(), Some("z".to_string()));
+        assert_eq!(db.remove("email").unwrap(), 0);
+        assert_eq!(db.remove("name").unwrap(), 1);
+
+        assert_eq!(db.get::<String>("name").unwrap(), None);
+        assert_eq!(db.get_document_as::<User>(&a).unwrap(), Some(user("a")));
+        assert_eq!(db.get_document_as::<User>(&b).unwrap(), Some(user("b")));
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    fn expired_documents_are_hidden_then_purged() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::LOG);
+        db.insert_with_ttl("short", &1, Duration::from_millis(300)).unwrap();
+        let long = db.insert_with_ttl("long", &2, Duration::from_secs(3600)).unwrap();
+        db.insert("kept", &3).unwrap();
+
+        drop(db);
+        let mut db = reopen(&path, LemonStorageMode::LOG);
+        assert_eq!(db.get::<u32>("short").unwrap(), Some(1));
+
+        std::thread::sleep(Duration::from_millis(350));
+        assert_eq!(db.get::<u32>("short").unwrap(), None);
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.purge_expired().unwrap(), 1);
+        assert_eq!(db.purge_expired().unwrap(), 0);
+
+        drop(db);
+        let db = reopen(&path, LemonStorageMode::LOG);
+        assert!(db.state().table("_table").unwrap().get(&long).is_some());
+        assert!(db.state().expiry.contains_key(&long));
+        assert_eq!(db.state().expiry.len(), 1);
+        assert_eq!(db.get::<u32>("kept").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn table_ttl_survives_reopen_and_rename() {
+        let (_dir, path, db) = temp_db(LemonStorageMode::SNAPSHOT);
+        let mut sessions = db.table("session").unwrap();
+        sessions.insert("a", &1).unwrap();
+        sessions.set_default_ttl("session", Some(Duration::from_millis(300))).unwrap();
+        // An explicit expiry wins over the ttl of the table
+        sessions.insert_with_ttl("pinned", &2, Duration::from_secs(3600)).unwrap();
+        sessions.rename_table("session", "login").unwrap();
+
+        drop(sessions);
+        drop(db);
+        let mut db = reopen(&path, LemonStorageMode::SNAPSHOT);
+        let logins = db.table("login").unwrap();
+        assert_eq!(logins.get::<u32>("a").unwrap(), Some(1));
+
+        std::thread::sleep(Duration::from_millis(350));
+        assert_eq!(logins.get::<u32>("a").unwrap(), None);
+        assert_eq!(logins.get::<u32>("pinned").unwrap(), Some(2));
+        assert_eq!(db.purge_expired().unwrap(), 1);
+        assert_eq!(logins.len(), 1);
+    }
+
+    #[test]
+    fn log_is_compacted_by_appended_bytes() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::LOG);
+        for i in 0..5000u32 {
+            db.set("counter", &i).unwrap();
+        }
+        // The log is started over once the sets outgrow the snapshot
+        let size = fs::metadata(&path).unwrap().len();
+        assert!(size < 2 * MIN_APPENDED_BYTES, "{} bytes", size);
+
+        db.set_compaction_ratio(None);
+        for i in 0..2000u32 {
+            db.set("counter", &i).unwrap();
+        }
+        assert!(db.compact().unwrap() > 0);
+
+        drop(db);
+        let db = reopen(&path, LemonStorageMode::LOG);
+        assert_eq!(db.get::<u32>("counter").unwrap(), Some(1999));
     }
-    
 }
@@ -20,11 +20,19 @@
 
 
 use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{Result, Context};
 
 use super::id::LemonId;
+use crate::LemonSerializer;
 
 type Data = HashMap<String, Vec<u8>>;
 
+// A single id generator shared by every document so that documents
+// created within the same millisecond still get a unique id.
+static ID: Mutex<Option<LemonId>> = Mutex::new(None);
+
 #[derive(Debug, Clone)]
 pub struct LemonDocument {
     pub id: String,
@@ -34,8 +42,9 @@ impl LemonDocument {
 
     pub fn new() -> LemonDocument
     {
+        let mut id = ID.lock().unwrap();
         LemonDocument {
-            id: LemonId::new("id").gen()
+            id: id.get_or_insert_with(|| LemonId::new("id")).gen()
         }
     }
 
@@ -46,4 +55,43 @@ impl LemonDocument {
 
         Ok(map)
     }
+
+    pub fn set_fields(&mut self, fields: Vec<(String, Vec<u8>)>) -> Result<Data, String>
+    {
+        if fields.is_empty() {
+            return Err("A document needs at least one field".to_string());
+        }
+
+        Ok(fields.into_iter().collect())
+    }
+}
+
+/// Decode every field of a document into a generic value tree with the
+/// serializer the document was written with.
+pub(crate) fn decode_fields(
+    serializer: &LemonSerializer,
+    data: &Data,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    decode_fields_where(serializer, data, |_| true)
+}
+
+/// Same as `decode_fields` but only the fields accepted by `wanted` are
+/// deserialized, the others are left out of the value tree.
+pub(crate) fn decode_fields_where<F>(
+    serializer: &LemonSerializer,
+    data: &Data,
+    wanted: F,
+) -> Result<serde_json::Map<String, serde_json::Value>>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut fields = serde_json::Map::new();
+    for (key, raw) in data.iter().filter(|(key, _)| wanted(key)) {
+        let value = serializer
+            .deserialize::<serde_json::Value>(raw)
+            .with_context(|| format!("Failed to deserialize the field `{}`", key))?;
+        fields.insert(key.clone(), value);
+    }
+
+    Ok(fields)
 }
@@ -0,0 +1,122 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::{error::Error, fmt, path::PathBuf};
+
+/// Errors callers may want to tell apart from the others.
+///
+/// They are returned wrapped in an `anyhow::Error` like every other error
+/// of the crate, use `downcast_ref::<LemonError>()` to match on them.
+///
+/// # Examples
+///
+/// ```ignore
+/// use lemondb::LemonError;
+///
+/// match db.insert_document(&user) {
+///     Err(err) => match err.downcast_ref::<LemonError>() {
+///         Some(LemonError::ConstraintViolation { field, .. }) => {
+///             println!("{} is already taken", field)
+///         },
+///         _ => return Err(err),
+///     },
+///     Ok(id) => println!("{}", id),
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LemonError {
+    /// A write would give two documents the same value of a field
+    /// with a unique index.
+    ConstraintViolation {
+        table: String,
+        field: String,
+        value: String,
+    },
+    /// The database file can't be read from `offset` on. `frame` is the
+    /// number of valid frames before it.
+    Corrupted {
+        offset: u64,
+        frame: usize,
+        reason: String,
+    },
+    /// The database file was written by a newer version of the format
+    /// than `supported`.
+    UnsupportedVersion {
+        version: u16,
+        supported: u16,
+    },
+    /// The document ids of the database file were generated from another
+    /// snowflake epoch than `supported`.
+    UnsupportedEpoch {
+        epoch: u64,
+        supported: u64,
+    },
+    /// The database at `path` is already open, by another process or
+    /// another `open` of this one.
+    Locked {
+        path: PathBuf,
+    },
+    /// The file at `path` has no header and can't be read by any
+    /// serializer, it's not a database or a legacy one of an unknown
+    /// serializer.
+    UnknownFormat {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for LemonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LemonError::ConstraintViolation { table, field, value } => write!(
+                f,
+                "Unique constraint violated: `{}.{}` already holds {}",
+                table, field, value
+            ),
+            LemonError::Corrupted { offset, frame, reason } => write!(
+                f,
+                "The database file is corrupted at byte {} (frame {}): {}",
+                offset, frame, reason
+            ),
+            LemonError::UnsupportedVersion { version, supported } => write!(
+                f,
+                "The database file format version {} is newer than the supported version {}",
+                version, supported
+            ),
+            LemonError::UnsupportedEpoch { epoch, supported } => write!(
+                f,
+                "The document ids were generated from the epoch {}, this version uses {}",
+                epoch, supported
+            ),
+            LemonError::Locked { path } => write!(
+                f,
+                "The database `{}` is locked, it is already open elsewhere",
+                path.display()
+            ),
+            LemonError::UnknownFormat { path } => write!(
+                f,
+                "`{}` is not a lemondb file or a legacy one of an unknown serializer",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Error for LemonError {}
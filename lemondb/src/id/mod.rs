@@ -1,7 +1,7 @@
 use crate::utils::now_timestamp;
 use base64;
 
-const DEFAULT_EPOCH: u64 = 1_666_595_382;
+pub(crate) const DEFAULT_EPOCH: u64 = 1_666_595_382;
 
 #[derive(Clone, Debug)]
 pub struct Snowflake {
@@ -39,11 +39,37 @@ impl LemonId {
         let p = &self.prefix;
         format!("{}_{}", p, base64::encode(sf).replace("=", ""))
     }
+
+    /// Recover the snowflake of an id generated by `gen`.
+    /// Return `None` if the id isn't a valid LemonId.
+    pub fn decode(id: &str) -> Option<u64> {
+        let (_, encoded) = id.split_once('_')?;
+        let raw = base64::decode_config(encoded, base64::STANDARD_NO_PAD).ok()?;
+        std::str::from_utf8(&raw).ok()?.parse::<u64>().ok()
+    }
+
+    /// Recover the creation time of an id generated by `gen`, in
+    /// milliseconds since the unix epoch like `utils::now_timestamp`.
+    /// Return `None` if the id isn't a valid LemonId.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use lemondb::id::LemonId;
+    ///
+    /// let id = db.insert_document(&user).unwrap();
+    /// let created = LemonId::created_at(&id).unwrap();
+    /// ```
+    pub fn created_at(id: &str) -> Option<u64> {
+        let sf = LemonId::decode(id)?;
+        let decoded = Snowflake::new(Some(DEFAULT_EPOCH)).decode(&sf.to_string());
+        Some(decoded.timestamp)
+    }
 }
 
 impl Snowflake {
     pub fn new(epoch: Option<u64>) -> Snowflake {
-        let e = epoch.or(Some(DEFAULT_EPOCH)).unwrap();
+        let e = epoch.unwrap_or(DEFAULT_EPOCH);
         Snowflake {
             epoch: e,
             seq: 0,
@@ -56,11 +82,12 @@ impl Snowflake {
         self.gen_with_ts(now_timestamp())
     }
 
-    pub fn gen_with_ts(&mut self, timestamp: u64) -> String {
+    pub fn gen_with_ts(&mut self, mut timestamp: u64) -> String {
         if self.seq >= 4095 && timestamp == self.last_sequence_exhaustion {
             while now_timestamp() - timestamp < 1 {
                 continue;
             }
+            timestamp = now_timestamp();
         }
 
         let sf = ((timestamp - self.epoch) << 22)
@@ -0,0 +1,410 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Bound,
+};
+
+use serde_json::Value;
+
+use crate::{
+    document::decode_fields_where,
+    query::field::{lookup, root, total_order},
+    LemonSerializer,
+};
+
+pub(crate) use crate::index::text::TextIndex;
+
+mod text;
+
+type Data = HashMap<String, Vec<u8>>;
+
+/// The reserved table holding the index definitions, so they are
+/// persisted in the db file with the rest of the data.
+pub(crate) const INDEX_TABLE: &str = "__lemon_indexes";
+
+/// How the values of an index are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IndexKind {
+    /// Equality lookups only
+    Hash,
+    /// Values are also kept sorted for range and prefix scans
+    Ordered,
+    /// Strings are also split into terms for full-text search
+    Text,
+}
+
+impl IndexKind {
+
+    fn name(&self) -> &'static str {
+        match self {
+            IndexKind::Hash => "hash",
+            IndexKind::Ordered => "ordered",
+            IndexKind::Text => "text",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<IndexKind> {
+        match name {
+            "hash" => Some(IndexKind::Hash),
+            "ordered" => Some(IndexKind::Ordered),
+            "text" => Some(IndexKind::Text),
+            _ => None,
+        }
+    }
+}
+
+/// A value kept in the B-tree of an ordered index, sorted with
+/// the same total order as `Cursor::sort_by`.
+#[derive(Debug, Clone)]
+struct SortKey(Value);
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &SortKey) -> Ordering {
+        total_order(Some(&self.0), Some(&other.0))
+    }
+}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &SortKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SortKey {
+    fn eq(&self, other: &SortKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortKey {}
+
+/// An in-memory index from the value of a field to the ids of the
+/// documents holding it. A unique index rejects two documents holding
+/// the same value, an ordered index also answers range scans and a
+/// text index full-text searches.
+#[derive(Debug, Clone)]
+pub(crate) struct LemonIndex {
+    pub table: String,
+    pub field: String,
+    pub unique: bool,
+    pub kind: IndexKind,
+    keys: HashMap<String, HashSet<String>>,
+    sorted: BTreeMap<SortKey, HashSet<String>>,
+    text: TextIndex,
+    documents: HashMap<String, Value>,
+}
+
+impl LemonIndex {
+
+    pub fn new(definition: &IndexDefinition) -> LemonIndex {
+        LemonIndex {
+            table: definition.table.clone(),
+            field: definition.field.clone(),
+            unique: definition.unique,
+            kind: definition.kind,
+            keys: HashMap::new(),
+            sorted: BTreeMap::new(),
+            text: TextIndex::default(),
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Index the document, documents without the field are left out
+    pub fn insert(&mut self, id: &str, data: &Data, serializer: &LemonSerializer) {
+        let value = match field_value(data, &self.field, serializer) {
+            Some(value) => value,
+            None => return,
+        };
+
+        self.keys.entry(index_key(&value))
+            .or_default()
+            .insert(id.to_string());
+        if self.kind == IndexKind::Ordered {
+            self.sorted.entry(SortKey(value.clone()))
+                .or_default()
+                .insert(id.to_string());
+        }
+        if self.kind == IndexKind::Text {
+            self.text.insert(id, &value);
+        }
+        self.documents.insert(id.to_string(), value);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let value = match self.documents.remove(id) {
+            Some(value) => value,
+            None => return,
+        };
+
+        let key = index_key(&value);
+        if let Some(ids) = self.keys.get_mut(&key) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.keys.remove(&key);
+            }
+        }
+
+        let key = SortKey(value);
+        if let Some(ids) = self.sorted.get_mut(&key) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.sorted.remove(&key);
+            }
+        }
+
+        self.text.remove(id);
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.sorted.clear();
+        self.text.clear();
+        self.documents.clear();
+    }
+
+    /// Check the unique constraint for the document about to be written.
+    /// Return the conflicting value if another document already holds it.
+    pub fn conflict(&self, id: &str, data: &Data, serializer: &LemonSerializer) -> Option<Value> {
+        if !self.unique {
+            return None;
+        }
+
+        let value = field_value(data, &self.field, serializer)?;
+        let taken = self.get(&value).any(|other| other != id);
+        if taken {
+            return Some(value);
+        }
+        None
+    }
+
+    /// The id of a document holding the same value as another one
+    /// in a unique index
+    pub fn duplicate(&self) -> Option<&String> {
+        if !self.unique {
+            return None;
+        }
+
+        self.keys.values()
+            .find(|ids| ids.len() > 1)
+            .and_then(|ids| ids.iter().next())
+    }
+
+    /// The ids of the documents where the field is equal to `value`
+    pub fn get(&self, value: &Value) -> impl Iterator<Item = &String> {
+        self.keys.get(&index_key(value))
+            .into_iter()
+            .flatten()
+    }
+
+    /// The ids of the documents where the field is within the bounds,
+    /// or `None` if the index isn't ordered.
+    pub fn range(&self, lower: Bound<&Value>, upper: Bound<&Value>) -> Option<Vec<String>> {
+        if self.kind != IndexKind::Ordered {
+            return None;
+        }
+
+        // `BTreeMap::range` panics on inverted or empty excluded bounds
+        if let (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) = (lower, upper) {
+            let empty = match total_order(Some(start), Some(end)) {
+                Ordering::Greater => true,
+                Ordering::Equal => !matches!((lower, upper), (Bound::Included(_), Bound::Included(_))),
+                Ordering::Less => false,
+            };
+            if empty {
+                return Some(Vec::new());
+            }
+        }
+
+        let bound = |bound: Bound<&Value>| bound.map(|value| SortKey(value.clone()));
+        let ids = self.sorted.range((bound(lower), bound(upper)))
+            .flat_map(|(_, ids)| ids.iter().cloned())
+            .collect();
+        Some(ids)
+    }
+
+    /// The BM25 score of the documents matching the search,
+    /// or `None` if the index isn't a text index.
+    pub fn search(&self, query: &str) -> Option<HashMap<String, f64>> {
+        if self.kind != IndexKind::Text {
+            return None;
+        }
+        Some(self.text.search(query))
+    }
+
+    /// The ids of the documents where the field is a string starting
+    /// with `prefix`, or `None` if the index isn't ordered.
+    pub fn prefix(&self, prefix: &str) -> Option<Vec<String>> {
+        if self.kind != IndexKind::Ordered {
+            return None;
+        }
+
+        let start = SortKey(Value::String(prefix.to_string()));
+        let ids = self.sorted.range(start..)
+            .take_while(|(key, _)| matches!(&key.0, Value::String(value) if value.starts_with(prefix)))
+            .flat_map(|(_, ids)| ids.iter().cloned())
+            .collect();
+        Some(ids)
+    }
+}
+
+/// An index definition as persisted in `INDEX_TABLE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexDefinition {
+    pub table: String,
+    pub field: String,
+    pub unique: bool,
+    pub kind: IndexKind,
+}
+
+impl IndexDefinition {
+
+    pub fn encode(&self, serializer: &LemonSerializer) -> Result<Data, String> {
+        let mut data = HashMap::new();
+        data.insert("table".to_string(), serializer.serialize(&self.table)?);
+        data.insert("field".to_string(), serializer.serialize(&self.field)?);
+        data.insert("unique".to_string(), serializer.serialize(&self.unique)?);
+        data.insert("kind".to_string(), serializer.serialize(&self.kind.name())?);
+        Ok(data)
+    }
+
+    pub fn decode(data: &Data, serializer: &LemonSerializer) -> Option<IndexDefinition> {
+        let text = |key: &str| field_value(data, key, serializer)
+            .and_then(|value| value.as_str().map(str::to_string));
+
+        Some(IndexDefinition {
+            table: text("table")?,
+            field: text("field")?,
+            unique: field_value(data, "unique", serializer)?.as_bool()?,
+            kind: IndexKind::from_name(&text("kind")?)?,
+        })
+    }
+}
+
+/// Decode the value of a field, only the top-level field holding it
+/// is deserialized.
+pub(crate) fn field_value(data: &Data, field: &str, serializer: &LemonSerializer) -> Option<Value> {
+    let fields = decode_fields_where(serializer, data, |key| key == root(field)).ok()?;
+    lookup(&Value::Object(fields), field).cloned()
+}
+
+/// The key of a value in an index. Numbers are keyed by their value so
+/// `30` and `30.0` land on the same key, like they do in queries.
+pub(crate) fn index_key(value: &Value) -> String {
+    match value {
+        Value::Number(number) => match number.as_f64() {
+            Some(number) => format!("n:{}", number),
+            None => format!("n:{}", number),
+        },
+        value => value.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use serde_json::json;
+
+    use crate::{
+        LemonError,
+        LemonStorageMode,
+        testing::{reopen, temp_db},
+    };
+
+    fn violation(err: anyhow::Error) -> (String, String) {
+        match err.downcast_ref::<LemonError>() {
+            Some(LemonError::ConstraintViolation { field, value, .. }) => (field.clone(), value.clone()),
+            _ => panic!("Expected a constraint violation, got {:#}", err),
+        }
+    }
+
+    #[test]
+    fn unique_index_rejects_duplicate_documents() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::LOG);
+        db.create_unique_index("_table", "email").unwrap();
+        db.insert_document(&json!({ "name": "a", "email": "a@lemon.db" })).unwrap();
+
+        let err = db.insert_document(&json!({ "name": "b", "email": "a@lemon.db" })).unwrap_err();
+        assert_eq!(violation(err), ("email".to_string(), "\"a@lemon.db\"".to_string()));
+        assert_eq!(db.len(), 1);
+
+        drop(db);
+        let mut db = reopen(&path, LemonStorageMode::LOG);
+        assert_eq!(db.len(), 1);
+        // The index is rebuilt on open
+        assert!(db.insert_document(&json!({ "email": "a@lemon.db" })).is_err());
+        assert!(db.insert_document(&json!({ "email": "b@lemon.db" })).is_ok());
+    }
+
+    #[test]
+    fn unique_index_rejects_upserts() {
+        let (_dir, _, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.create_unique_index("_table", "email").unwrap();
+        db.insert_document(&json!({ "name": "a", "email": "a@lemon.db" })).unwrap();
+
+        let err = db.set("email", &"a@lemon.db").unwrap_err();
+        violation(err);
+        assert_eq!(db.len(), 1);
+
+        // Writing the same value again to the same document is fine
+        db.set("email", &"b@lemon.db").unwrap();
+        db.set("email", &"b@lemon.db").unwrap();
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    fn extend_writes_nothing_on_a_violation() {
+        let (_dir, _, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.create_unique_index("_table", "email").unwrap();
+        db.insert_document(&json!({ "name": "a", "email": "a@lemon.db" })).unwrap();
+
+        let other = db.clone();
+        let mut handle = db.clone();
+        let extended = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle.extend(vec![("name", "b"), ("email", "a@lemon.db")]);
+        }));
+        assert!(extended.is_err());
+
+        // Nothing was written and the state isn't poisoned
+        assert_eq!(other.len(), 1);
+        assert_eq!(other.get::<String>("name").unwrap(), None);
+
+        db.extend(vec![("name", "b"), ("email", "b@lemon.db")]);
+        assert_eq!(db.len(), 3);
+    }
+
+    #[test]
+    fn unique_index_needs_distinct_values() {
+        let (_dir, _, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.insert_document(&json!({ "country": "fr" })).unwrap();
+        db.insert_document(&json!({ "country": "fr" })).unwrap();
+
+        assert!(db.create_unique_index("_table", "country").is_err());
+        db.create_index("_table", "country").unwrap();
+    }
+}
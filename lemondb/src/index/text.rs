@@ -0,0 +1,175 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// BM25 term frequency saturation
+const K1: f64 = 1.2;
+/// BM25 document length normalization
+const B: f64 = 0.75;
+
+/// Common english words left out of the index and the queries
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from",
+    "has", "have", "in", "into", "is", "it", "its", "of", "on", "or", "so",
+    "that", "the", "their", "then", "there", "these", "this", "to", "was",
+    "were", "will", "with",
+];
+
+/// An inverted index from the terms of a text field to the documents
+/// holding them, used to rank the documents of a search.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TextIndex {
+    /// The number of occurrences of a term in each document
+    postings: HashMap<String, HashMap<String, usize>>,
+    /// The number of terms of each document
+    lengths: HashMap<String, usize>,
+    total_length: usize,
+}
+
+impl TextIndex {
+
+    /// Index the strings of the value, the value can be a string
+    /// or an array of strings.
+    pub fn insert(&mut self, id: &str, value: &Value) {
+        let mut terms = Vec::new();
+        match value {
+            Value::String(text) => terms.extend(tokenize(text)),
+            Value::Array(items) => items.iter()
+                .filter_map(Value::as_str)
+                .for_each(|text| terms.extend(tokenize(text))),
+            _ => return,
+        }
+
+        self.lengths.insert(id.to_string(), terms.len());
+        self.total_length += terms.len();
+        for term in terms {
+            *self.postings.entry(term)
+                .or_default()
+                .entry(id.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let length = match self.lengths.remove(id) {
+            Some(length) => length,
+            None => return,
+        };
+
+        self.total_length -= length;
+        self.postings.retain(|_, documents| {
+            documents.remove(id);
+            !documents.is_empty()
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.postings.clear();
+        self.lengths.clear();
+        self.total_length = 0;
+    }
+
+    /// Score the documents holding any term of the query with BM25
+    pub fn search(&self, query: &str) -> HashMap<String, f64> {
+        let mut scores = HashMap::new();
+        if self.lengths.is_empty() {
+            return scores;
+        }
+
+        let count = self.lengths.len() as f64;
+        let average = (self.total_length as f64 / count).max(1.0);
+
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+
+        for term in terms {
+            let documents = match self.postings.get(&term) {
+                Some(documents) => documents,
+                None => continue,
+            };
+
+            let frequency = documents.len() as f64;
+            let idf = (1.0 + (count - frequency + 0.5) / (frequency + 0.5)).ln();
+
+            for (id, occurrences) in documents {
+                let occurrences = *occurrences as f64;
+                let length = self.lengths.get(id).copied().unwrap_or_default() as f64;
+                let score = idf * occurrences * (K1 + 1.0)
+                    / (occurrences + K1 * (1.0 - B + B * length / average));
+
+                *scores.entry(id.clone()).or_insert(0.0) += score;
+            }
+        }
+
+        scores
+    }
+}
+
+/// Split the text into lowercase stemmed terms, without the stop words
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .map(|word| stem(&word))
+        .collect()
+}
+
+/// Strip the most common english suffixes so `databases` and `database`
+/// or `indexing` and `indexed` end up on the same term. Short and
+/// non-ascii words are kept as they are.
+fn stem(word: &str) -> String {
+    let mut word = word.to_string();
+    if word.len() <= 3 || !word.is_ascii() {
+        return word;
+    }
+
+    if let Some(stem) = word.strip_suffix("ies") {
+        word = format!("{}y", stem);
+    } else if ["sses", "xes", "zes", "ches", "shes"].iter().any(|suffix| word.ends_with(suffix)) {
+        word.truncate(word.len() - 2);
+    } else if word.ends_with('s') && !["ss", "us", "is"].iter().any(|suffix| word.ends_with(suffix)) {
+        word.pop();
+    }
+
+    for suffix in ["ing", "ed", "ly"] {
+        if word.ends_with(suffix) && word.len() - suffix.len() >= 3 {
+            word.truncate(word.len() - suffix.len());
+
+            // running -> run
+            let bytes = word.as_bytes();
+            let last = bytes[bytes.len() - 1];
+            if suffix != "ly" && last == bytes[bytes.len() - 2] && !b"lsz".contains(&last) {
+                word.pop();
+            }
+            break;
+        }
+    }
+
+    if word.len() > 4 && word.ends_with('e') {
+        word.pop();
+    }
+    word
+}
@@ -1,31 +1,31 @@
-///! LemonDb
-///! =======
-///! 
-///! LemonDB is a lightweight no-sql document-oriented key-value storage database focusing in
-///! performance and easability. It is heavily inspired with [lemondb](https://github.com/riyuzenn/lemondb) with added features
-///!
-///! The logic is simple. All data were act as document and stored in a tables.
-///! Let's say you have a table of users with corresponding documents and value 
-///! (key: name, value: John Doe). The diagram shows how it stores the data
-///!
-///! 
-///! |-----------------------------------------------|
-///! | Table: User                                   |
-///! |-----------------------------------------------|
-///! | Document                                      |
-///! |   |                                           |
-///! |   |-- id                                      |
-///! |   |-- Data                                    |
-///! |       |                                       |
-///! |       |-- key (name)                          |
-///! |       |   |-- &str                            |
-///! |       |                                       |
-///! |       |-- value (John Doe)                    |
-///! |           |-- Any serializable type (serde)   |
-///! |                                               |
-///! |-----------------------------------------------|
-///!  \----------------------------------------------\
-///!
+//! LemonDb
+//! =======
+//! 
+//! LemonDB is a lightweight no-sql document-oriented key-value storage database focusing in
+//! performance and easability. It is heavily inspired with [lemondb](https://github.com/riyuzenn/lemondb) with added features
+//!
+//! The logic is simple. All data were act as document and stored in a tables.
+//! Let's say you have a table of users with corresponding documents and value 
+//! (key: name, value: John Doe). The diagram shows how it stores the data
+//!
+//! 
+//! |-----------------------------------------------|
+//! | Table: User                                   |
+//! |-----------------------------------------------|
+//! | Document                                      |
+//! |   |                                           |
+//! |   |-- id                                      |
+//! |   |-- Data                                    |
+//! |       |                                       |
+//! |       |-- key (name)                          |
+//! |       |   |-- &str                            |
+//! |       |                                       |
+//! |       |-- value (John Doe)                    |
+//! |           |-- Any serializable type (serde)   |
+//! |                                               |
+//! |-----------------------------------------------|
+//!  \----------------------------------------------\
+//!
 
 /*
  *
@@ -51,18 +51,26 @@
 pub use crate::db::{
     LemonDb,
     LemonOption,
-    LemonDumpRule
+    LemonDumpRule,
+    LemonStorageMode
 };
 
 pub use crate::serializer::Serializer;
 
+pub use crate::error::LemonError;
+
 pub(crate) use crate::serializer::LemonSerializer;
 
 pub mod db;
 pub mod utils;
 pub mod id;
 pub mod serializer;
+pub mod query;
+pub mod error;
 
 mod document;
+mod index;
 mod storage;
-mod query;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,282 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use anyhow::{Result, Context};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    index::index_key,
+    query::{
+        Cursor,
+        field::{lookup, total_order},
+    },
+};
+
+/// The documents of a cursor grouped by the value of a field.
+///
+/// Created with `Cursor::group_by`, each aggregation returns a map from
+/// the group key to the aggregated value of the group.
+#[derive(Debug, Clone)]
+pub struct Groups<K> {
+    groups: Vec<(K, Vec<Value>)>,
+}
+
+impl<V> Cursor<V>
+where
+    V: DeserializeOwned,
+{
+    /// Sum the numeric values of the field. Documents where the field is
+    /// missing or isn't a number are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - The field name or dotted path
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let total = db.find::<Order>()
+    ///     .matching(Field("paid").eq(true))
+    ///     .sum("price");
+    /// ```
+    pub fn sum(self, field: &str) -> f64 {
+        numbers(&self.values_of(field)).sum()
+    }
+
+    /// The average of the numeric values of the field, `None` if there
+    /// is no numeric value.
+    pub fn avg(self, field: &str) -> Option<f64> {
+        average(&self.values_of(field))
+    }
+
+    /// The smallest value of the field decoded into `T`, `None` if no
+    /// document has the field.
+    pub fn min<T: DeserializeOwned>(self, field: &str) -> Result<Option<T>> {
+        let values = self.values_of(field);
+        decode(field, extremum(&values, Ordering::Less))
+    }
+
+    /// The largest value of the field decoded into `T`, `None` if no
+    /// document has the field.
+    pub fn max<T: DeserializeOwned>(self, field: &str) -> Result<Option<T>> {
+        let values = self.values_of(field);
+        decode(field, extremum(&values, Ordering::Greater))
+    }
+
+    /// Every distinct value of the field decoded into `T`, in the order
+    /// they are first seen.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let countries = db.find::<User>().distinct::<String>("country").unwrap();
+    /// ```
+    pub fn distinct<T: DeserializeOwned>(self, field: &str) -> Result<Vec<T>> {
+        let mut keys = HashSet::new();
+        let mut seen: Vec<Value> = Vec::new();
+        for value in self.values_of(field) {
+            if keys.insert(index_key(&value)) {
+                seen.push(value);
+            }
+        }
+
+        seen.into_iter()
+            .map(|value| decode(field, Some(&value)).map(Option::unwrap))
+            .collect()
+    }
+
+    /// Group the documents by the value of the field decoded into `K`.
+    /// Documents without the field are grouped under `null`, so use an
+    /// `Option` key to keep them.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let users_per_country = db.find::<User>()
+    ///     .group_by::<String>("country")
+    ///     .unwrap()
+    ///     .count();
+    /// ```
+    pub fn group_by<K>(mut self, field: &str) -> Result<Groups<K>>
+    where
+        K: DeserializeOwned,
+    {
+        self.extra_paths.push(field.to_string());
+
+        // The groups in the order they are first seen, found by the
+        // same key as in the indexes
+        let mut groups: Vec<(Value, Vec<Value>)> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        while let Some((_, document, _)) = self.next_document() {
+            let key = lookup(&document, field).cloned().unwrap_or(Value::Null);
+            let position = *positions.entry(index_key(&key)).or_insert_with(|| {
+                groups.push((key, Vec::new()));
+                groups.len() - 1
+            });
+            groups[position].1.push(document);
+        }
+
+        let groups = groups.into_iter()
+            .map(|(key, documents)| {
+                let key = decode(field, Some(&key))?.unwrap();
+                Ok((key, documents))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Groups { groups })
+    }
+
+    /// The values of the field in every matching document
+    fn values_of(mut self, field: &str) -> Vec<Value> {
+        self.extra_paths.push(field.to_string());
+
+        let mut values = Vec::new();
+        while let Some((_, document, _)) = self.next_document() {
+            if let Some(value) = lookup(&document, field) {
+                values.push(value.clone());
+            }
+        }
+        values
+    }
+}
+
+impl<K> Groups<K>
+where
+    K: Eq + Hash,
+{
+    /// The number of documents in each group
+    pub fn count(self) -> HashMap<K, usize> {
+        self.groups.into_iter()
+            .map(|(key, documents)| (key, documents.len()))
+            .collect()
+    }
+
+    /// The sum of the numeric values of the field in each group
+    pub fn sum(self, field: &str) -> HashMap<K, f64> {
+        self.groups.into_iter()
+            .map(|(key, documents)| (key, numbers(&values_in(&documents, field)).sum()))
+            .collect()
+    }
+
+    /// The average of the numeric values of the field in each group
+    pub fn avg(self, field: &str) -> HashMap<K, Option<f64>> {
+        self.groups.into_iter()
+            .map(|(key, documents)| (key, average(&values_in(&documents, field))))
+            .collect()
+    }
+
+    /// The smallest value of the field decoded into `T` in each group
+    pub fn min<T: DeserializeOwned>(self, field: &str) -> Result<HashMap<K, Option<T>>> {
+        self.groups.into_iter()
+            .map(|(key, documents)| {
+                let values = values_in(&documents, field);
+                Ok((key, decode(field, extremum(&values, Ordering::Less))?))
+            })
+            .collect()
+    }
+
+    /// The largest value of the field decoded into `T` in each group
+    pub fn max<T: DeserializeOwned>(self, field: &str) -> Result<HashMap<K, Option<T>>> {
+        self.groups.into_iter()
+            .map(|(key, documents)| {
+                let values = values_in(&documents, field);
+                Ok((key, decode(field, extremum(&values, Ordering::Greater))?))
+            })
+            .collect()
+    }
+}
+
+fn values_in(documents: &[Value], field: &str) -> Vec<Value> {
+    documents.iter()
+        .filter_map(|document| lookup(document, field).cloned())
+        .collect()
+}
+
+fn numbers(values: &[Value]) -> impl Iterator<Item = f64> + '_ {
+    values.iter().filter_map(Value::as_f64)
+}
+
+fn average(values: &[Value]) -> Option<f64> {
+    let (sum, count) = numbers(values)
+        .fold((0.0, 0usize), |(sum, count), value| (sum + value, count + 1));
+
+    if count == 0 {
+        return None;
+    }
+    Some(sum / count as f64)
+}
+
+fn extremum(values: &[Value], wanted: Ordering) -> Option<&Value> {
+    values.iter().reduce(|best, value| {
+        if total_order(Some(value), Some(best)) == wanted {
+            value
+        } else {
+            best
+        }
+    })
+}
+
+fn decode<T: DeserializeOwned>(field: &str, value: Option<&Value>) -> Result<Option<T>> {
+    value
+        .map(|value| {
+            T::deserialize(value)
+                .with_context(|| format!("Failed to deserialize the value of `{}`", field))
+        })
+        .transpose()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{LemonStorageMode, testing::temp_db};
+
+    #[test]
+    fn equal_numbers_share_a_group() {
+        let (_dir, _, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.insert_document(&json!({ "country": "fr", "n": 1 })).unwrap();
+        db.insert_document(&json!({ "country": "fr", "n": 1.0 })).unwrap();
+        db.insert_document(&json!({ "country": "de", "n": 2 })).unwrap();
+
+        let mut distinct = db.find::<serde_json::Value>().distinct::<f64>("n").unwrap();
+        distinct.sort_by(f64::total_cmp);
+        assert_eq!(distinct, [1.0, 2.0]);
+
+        let counts = db.find::<serde_json::Value>()
+            .group_by::<String>("country")
+            .unwrap()
+            .count();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["fr"], 2);
+        assert_eq!(counts["de"], 1);
+    }
+}
@@ -0,0 +1,365 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+
+use anyhow::{Result, Context};
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// A field of a document used to build a `Query`.
+///
+/// The field is given by its name or by a dotted path for nested values,
+/// such as `address.city`. Array elements can be reached by their index,
+/// such as `tags.0`.
+///
+/// # Examples
+///
+/// ```ignore
+/// use lemondb::query::Field;
+///
+/// let query = Field("age").gt(30)
+///     .and(Field("address.city").eq("Manila"));
+///
+/// let users = db.find::<User>().matching(query).collect::<Vec<_>>();
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Field<'a>(pub &'a str);
+
+/// A declarative filter over the fields of a document.
+///
+/// Stored values are inspected as generic value trees, so a query runs
+/// against any table regardless of the serializer it was written with.
+#[derive(Debug, Clone)]
+pub enum Query {
+    Eq(String, Value),
+    Ne(String, Value),
+    Gt(String, Value),
+    Gte(String, Value),
+    Lt(String, Value),
+    Lte(String, Value),
+    Range(String, Bound<Value>, Bound<Value>),
+    In(String, Vec<Value>),
+    Exists(String),
+    Contains(String, Value),
+    StartsWith(String, String),
+    Regex(String, Regex),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+impl<'a> Field<'a> {
+
+    /// The field is equal to `value`
+    pub fn eq<V: Into<Value>>(self, value: V) -> Query {
+        Query::Eq(self.0.to_string(), value.into())
+    }
+
+    /// The field exists and isn't equal to `value`
+    pub fn ne<V: Into<Value>>(self, value: V) -> Query {
+        Query::Ne(self.0.to_string(), value.into())
+    }
+
+    /// The field is greater than `value`
+    pub fn gt<V: Into<Value>>(self, value: V) -> Query {
+        Query::Gt(self.0.to_string(), value.into())
+    }
+
+    /// The field is greater than or equal to `value`
+    pub fn gte<V: Into<Value>>(self, value: V) -> Query {
+        Query::Gte(self.0.to_string(), value.into())
+    }
+
+    /// The field is less than `value`
+    pub fn lt<V: Into<Value>>(self, value: V) -> Query {
+        Query::Lt(self.0.to_string(), value.into())
+    }
+
+    /// The field is less than or equal to `value`
+    pub fn lte<V: Into<Value>>(self, value: V) -> Query {
+        Query::Lte(self.0.to_string(), value.into())
+    }
+
+    /// The field is within the range, such as `100..500` or `"A".."N"`.
+    /// Like `gt` and `lt`, only values of the same kind as the bounds match.
+    pub fn range<R, V>(self, range: R) -> Query
+    where
+        R: RangeBounds<V>,
+        V: Into<Value> + Clone,
+    {
+        let bound = |bound: Bound<&V>| bound.cloned().map(Into::into);
+        Query::Range(self.0.to_string(), bound(range.start_bound()), bound(range.end_bound()))
+    }
+
+    /// The field is equal to one of the `values`
+    pub fn in_<I, V>(self, values: I) -> Query
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<Value>,
+    {
+        Query::In(self.0.to_string(), values.into_iter().map(Into::into).collect())
+    }
+
+    /// The field exists in the document, even if it's null
+    pub fn exists(self) -> Query {
+        Query::Exists(self.0.to_string())
+    }
+
+    /// The field is an array holding `value` or a string holding
+    /// the `value` substring
+    pub fn contains<V: Into<Value>>(self, value: V) -> Query {
+        Query::Contains(self.0.to_string(), value.into())
+    }
+
+    /// The field is a string starting with `prefix`
+    pub fn starts_with(self, prefix: &str) -> Query {
+        Query::StartsWith(self.0.to_string(), prefix.to_string())
+    }
+
+    /// The field is a string matching the regular expression `pattern`.
+    /// Fail if the pattern is invalid.
+    pub fn regex(self, pattern: &str) -> Result<Query> {
+        let regex = Regex::new(pattern)
+            .with_context(|| format!("Invalid regular expression `{}`", pattern))?;
+        Ok(Query::Regex(self.0.to_string(), regex))
+    }
+}
+
+impl Query {
+
+    /// Match the documents matching both queries
+    pub fn and(self, other: Query) -> Query {
+        match self {
+            Query::And(mut queries) => {
+                queries.push(other);
+                Query::And(queries)
+            },
+            query => Query::And(vec![query, other]),
+        }
+    }
+
+    /// Match the documents matching any of the queries
+    pub fn or(self, other: Query) -> Query {
+        match self {
+            Query::Or(mut queries) => {
+                queries.push(other);
+                Query::Or(queries)
+            },
+            query => Query::Or(vec![query, other]),
+        }
+    }
+
+    /// Match the documents not matching the query
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Query {
+        Query::Not(Box::new(self))
+    }
+
+    /// Check if the document matches the query. The document is the
+    /// generic value tree of its fields.
+    pub fn matches(&self, document: &Value) -> bool {
+        match self {
+            Query::Eq(path, value) => lookup(document, path)
+                .is_some_and(|field| equals(field, value)),
+            Query::Ne(path, value) => lookup(document, path)
+                .is_some_and(|field| !equals(field, value)),
+            Query::Gt(path, value) => order(document, path, value) == Some(Ordering::Greater),
+            Query::Gte(path, value) => matches!(
+                order(document, path, value),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            Query::Lt(path, value) => order(document, path, value) == Some(Ordering::Less),
+            Query::Lte(path, value) => matches!(
+                order(document, path, value),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Query::Range(path, lower, upper) => lookup(document, path)
+                .is_some_and(|field| within(field, lower.as_ref(), upper.as_ref())),
+            Query::In(path, values) => lookup(document, path)
+                .is_some_and(|field| values.iter().any(|value| equals(field, value))),
+            Query::Exists(path) => lookup(document, path).is_some(),
+            Query::Contains(path, value) => match (lookup(document, path), value) {
+                (Some(Value::Array(items)), value) => items.iter().any(|item| equals(item, value)),
+                (Some(Value::String(field)), Value::String(value)) => field.contains(value.as_str()),
+                _ => false,
+            },
+            Query::StartsWith(path, prefix) => match lookup(document, path) {
+                Some(Value::String(field)) => field.starts_with(prefix.as_str()),
+                _ => false,
+            },
+            Query::Regex(path, regex) => match lookup(document, path) {
+                Some(Value::String(field)) => regex.is_match(field),
+                _ => false,
+            },
+            Query::And(queries) => queries.iter().all(|query| query.matches(document)),
+            Query::Or(queries) => queries.iter().any(|query| query.matches(document)),
+            Query::Not(query) => !query.matches(document),
+        }
+    }
+
+    /// Collect the paths of every field the query looks at
+    pub(crate) fn paths<'q>(&'q self, paths: &mut Vec<&'q str>) {
+        match self {
+            Query::Eq(path, _)
+            | Query::Ne(path, _)
+            | Query::Gt(path, _)
+            | Query::Gte(path, _)
+            | Query::Lt(path, _)
+            | Query::Lte(path, _)
+            | Query::Range(path, _, _)
+            | Query::In(path, _)
+            | Query::Exists(path)
+            | Query::Contains(path, _)
+            | Query::StartsWith(path, _)
+            | Query::Regex(path, _) => paths.push(path),
+            Query::And(queries) | Query::Or(queries) => queries.iter()
+                .for_each(|query| query.paths(paths)),
+            Query::Not(query) => query.paths(paths),
+        }
+    }
+}
+
+/// Resolve a dotted path such as `address.city` in a value tree.
+pub(crate) fn lookup<'v>(value: &'v Value, path: &str) -> Option<&'v Value> {
+    path.split('.').try_fold(value, |value, segment| match value {
+        Value::Object(map) => map.get(segment),
+        Value::Array(items) => segment.parse::<usize>().ok()
+            .and_then(|index| items.get(index)),
+        _ => None,
+    })
+}
+
+/// Set the value at a dotted path, creating the missing objects on the way.
+pub(crate) fn insert_path(map: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            let child = map.entry(head.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = child {
+                insert_path(child, rest, value);
+            }
+        },
+        None => {
+            map.insert(path.to_string(), value);
+        },
+    }
+}
+
+/// The top-level field of a dotted path
+pub(crate) fn root(path: &str) -> &str {
+    path.split('.').next().unwrap_or(path)
+}
+
+/// Compare two values of the same kind. Numbers are compared by their
+/// value regardless of their representation, `30` is equal to `30.0`.
+pub(crate) fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// A total order over values of any kind, used for sorting and by ordered
+/// indexes. Missing values come first, then `null`, booleans, numbers,
+/// strings, arrays and objects.
+///
+/// Strings are compared byte-wise, so timestamps order chronologically
+/// when stored as numbers or as RFC 3339 strings in the same timezone.
+/// Arrays are compared item by item and objects field by field, in the
+/// order of their field names.
+pub(crate) fn total_order(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: Option<&Value>) -> u8 {
+        match value {
+            None => 0,
+            Some(Value::Null) => 1,
+            Some(Value::Bool(_)) => 2,
+            Some(Value::Number(_)) => 3,
+            Some(Value::String(_)) => 4,
+            Some(Value::Array(_)) => 5,
+            Some(Value::Object(_)) => 6,
+        }
+    }
+
+    match (a, b) {
+        (Some(Value::Array(a)), Some(Value::Array(b))) => a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| total_order(Some(a), Some(b)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let mut a: Vec<_> = a.iter().collect();
+            let mut b: Vec<_> = b.iter().collect();
+            a.sort_by_key(|(key, _)| *key);
+            b.sort_by_key(|(key, _)| *key);
+
+            a.iter()
+                .zip(b.iter())
+                .map(|((a_key, a), (b_key, b))| {
+                    a_key.cmp(b_key).then_with(|| total_order(Some(a), Some(b)))
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        },
+        (Some(a), Some(b)) => compare(a, b)
+            .unwrap_or_else(|| rank(Some(a)).cmp(&rank(Some(b)))),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+fn equals(a: &Value, b: &Value) -> bool {
+    match compare(a, b) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => a == b,
+    }
+}
+
+/// Check the value is of the same kind as the bounds and within them
+pub(crate) fn within(value: &Value, lower: Bound<&Value>, upper: Bound<&Value>) -> bool {
+    let lower = match lower {
+        Bound::Included(bound) => matches!(
+            compare(value, bound),
+            Some(Ordering::Greater | Ordering::Equal)
+        ),
+        Bound::Excluded(bound) => compare(value, bound) == Some(Ordering::Greater),
+        Bound::Unbounded => true,
+    };
+    let upper = match upper {
+        Bound::Included(bound) => matches!(
+            compare(value, bound),
+            Some(Ordering::Less | Ordering::Equal)
+        ),
+        Bound::Excluded(bound) => compare(value, bound) == Some(Ordering::Less),
+        Bound::Unbounded => true,
+    };
+    lower && upper
+}
+
+fn order(document: &Value, path: &str, value: &Value) -> Option<Ordering> {
+    compare(lookup(document, path)?, value)
+}
@@ -0,0 +1,502 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::{
+    collections::HashMap,
+    ops::{Bound, RangeBounds},
+};
+
+use anyhow::{Result, Context, bail};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    document::decode_fields_where,
+    id::LemonId,
+    LemonSerializer,
+};
+
+pub use crate::query::{
+    field::{Field, Query},
+    aggregate::Groups,
+};
+
+pub mod field;
+pub mod aggregate;
+
+type Data = HashMap<String, Vec<u8>>;
+type Predicate<V> = Box<dyn Fn(&V) -> bool>;
+
+/// Load the documents of the table a cursor runs on. The queries are
+/// given so an index can narrow down the documents to be read.
+pub(crate) type Source = Box<dyn FnOnce(&[Query]) -> Vec<(String, Data)>>;
+
+/// The sort direction of `Cursor::sort_by`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// A page of documents returned by `Cursor::page`.
+///
+/// `next` is an opaque resume token to be given to `Cursor::after` for
+/// the next page, it is `None` on the last page.
+#[derive(Debug, Clone)]
+pub struct Page<V> {
+    pub items: Vec<(String, V)>,
+    pub next: Option<String>,
+}
+
+/// A lazy query over the documents of a table.
+///
+/// The cursor is created with `LemonDb::find` and yields every matching
+/// document as `(document_id, V)` in creation order. Documents are only
+/// read and decoded while the cursor is iterated, so it can be chained and
+/// collected or counted like any other iterator.
+pub struct Cursor<V> {
+    source: Option<Source>,
+    documents: Option<std::vec::IntoIter<(String, Data)>>,
+    after: Option<u64>,
+    created: (Bound<u64>, Bound<u64>),
+    serializer: LemonSerializer,
+    queries: Vec<Query>,
+    filters: Vec<Predicate<V>>,
+    sort: Option<(String, Order)>,
+    projection: Option<Vec<String>>,
+    sorted: Option<std::vec::IntoIter<(String, Value, V)>>,
+    extra_paths: Vec<String>,
+    skip: usize,
+    limit: Option<usize>,
+}
+
+impl<V> Cursor<V>
+where
+    V: DeserializeOwned,
+{
+    pub(crate) fn new(source: Source, serializer: LemonSerializer) -> Cursor<V> {
+        Cursor {
+            source: Some(source),
+            documents: None,
+            after: None,
+            created: (Bound::Unbounded, Bound::Unbounded),
+            serializer,
+            queries: Vec::new(),
+            filters: Vec::new(),
+            sort: None,
+            projection: None,
+            sorted: None,
+            extra_paths: Vec::new(),
+            skip: 0,
+            limit: None,
+        }
+    }
+
+    /// Only yield the documents matching the field query. The query is
+    /// checked on the stored values before they are deserialized into `V`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A query built from `Field`
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use lemondb::query::Field;
+    ///
+    /// let cursor = db.find::<User>()
+    ///     .matching(Field("age").gt(30).and(Field("name").starts_with("J")));
+    /// ```
+    pub fn matching(mut self, query: Query) -> Cursor<V> {
+        self.queries.push(query);
+        self
+    }
+
+    /// Only yield the documents where the field is within the range.
+    /// An ordered index on the field is used when there is one, see
+    /// `LemonDb::create_ordered_index`.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - The field name or dotted path
+    /// * `range` - The range of values, such as `100..500` or `"A"..="M"`
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let orders = db.find::<Order>()
+    ///     .range("total", 100..500)
+    ///     .collect::<Vec<_>>();
+    /// ```
+    pub fn range<R, T>(self, field: &str, range: R) -> Cursor<V>
+    where
+        R: RangeBounds<T>,
+        T: Into<Value> + Clone,
+    {
+        self.matching(Field(field).range(range))
+    }
+
+    /// Only yield the documents where the field is a string starting
+    /// with `prefix`. An ordered index on the field is used when there is one.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - The field name or dotted path
+    /// * `prefix` - The start of the string
+    ///
+    pub fn prefix(self, field: &str, prefix: &str) -> Cursor<V> {
+        self.matching(Field(field).starts_with(prefix))
+    }
+
+    /// Only yield the documents created from `start` up to, but not
+    /// including, `end`. The creation time is read from the document id,
+    /// no timestamp field is needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Milliseconds since the unix epoch
+    /// * `end` - Milliseconds since the unix epoch
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use lemondb::utils::now_timestamp;
+    ///
+    /// let now = now_timestamp();
+    /// let yesterday = db.find::<User>()
+    ///     .created_between(now - 2 * 86_400_000, now - 86_400_000)
+    ///     .collect::<Vec<_>>();
+    /// ```
+    pub fn created_between(mut self, start: u64, end: u64) -> Cursor<V> {
+        self.created = (Bound::Included(start), Bound::Excluded(end));
+        self
+    }
+
+    /// Only yield the documents created after `time`, such as the
+    /// documents inserted in the last hour.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - Milliseconds since the unix epoch
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use lemondb::utils::now_timestamp;
+    ///
+    /// let recent = db.find::<User>()
+    ///     .created_after(now_timestamp() - 3_600_000)
+    ///     .collect::<Vec<_>>();
+    /// ```
+    pub fn created_after(mut self, time: u64) -> Cursor<V> {
+        self.created = (Bound::Excluded(time), Bound::Unbounded);
+        self
+    }
+
+    /// Only yield the documents matching the predicate. Multiple
+    /// filters can be chained, a document has to match all of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - A function returning `true` for the documents to keep
+    ///
+    pub fn filter<F>(mut self, predicate: F) -> Cursor<V>
+    where
+        F: Fn(&V) -> bool + 'static,
+    {
+        self.filters.push(Box::new(predicate));
+        self
+    }
+
+    /// Sort the documents by the given field. Documents without the field
+    /// come first in ascending order. Sorting has to read every matching
+    /// document before the first one is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - The field name or dotted path to sort by
+    /// * `order` - `Order::Asc` or `Order::Desc`
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use lemondb::query::Order;
+    ///
+    /// let oldest = db.find::<User>()
+    ///     .sort_by("age", Order::Desc)
+    ///     .limit(10)
+    ///     .collect::<Vec<_>>();
+    /// ```
+    pub fn sort_by(mut self, field: &str, order: Order) -> Cursor<V> {
+        self.sort = Some((field.to_string(), order));
+        self
+    }
+
+    /// Only return the given fields of the documents. A field can be a
+    /// dotted path such as `address.city` for values stored as maps, it is
+    /// returned nested the same way (`{"address": {"city": ..}}`).
+    ///
+    /// Only the projected fields, and the ones needed by the queries and
+    /// the sort, are deserialized from the stored values.
+    ///
+    /// # Arguments
+    ///
+    /// * `fields` - The field names or dotted paths to return
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// # use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Contact {
+    ///     name: String,
+    ///     email: String,
+    /// }
+    ///
+    /// let contacts = db.find::<Contact>()
+    ///     .select(&["name", "email"])
+    ///     .collect::<Vec<_>>();
+    /// ```
+    pub fn select(mut self, fields: &[&str]) -> Cursor<V> {
+        self.projection = Some(fields.iter().map(|field| field.to_string()).collect());
+        self
+    }
+
+    /// Skip the first `n` matching documents
+    pub fn skip(mut self, n: usize) -> Cursor<V> {
+        self.skip = n;
+        self
+    }
+
+    /// Yield at most `n` documents
+    pub fn limit(mut self, n: usize) -> Cursor<V> {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Resume the cursor after the last document of a previous page.
+    /// Fail if the token wasn't returned by `page`.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The `next` token of a `Page`
+    ///
+    pub fn after(mut self, token: &str) -> Result<Cursor<V>> {
+        self.after = Some(decode_token(token)?);
+        Ok(self)
+    }
+
+    /// Return the next `size` documents and a token to resume from.
+    ///
+    /// Pages follow the document id order, which is the creation order, so
+    /// they stay stable while documents are written. It can't be combined
+    /// with `sort_by`.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The number of documents in the page
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    ///
+    /// let page = db.find::<User>().page(50).unwrap();
+    /// if let Some(token) = page.next {
+    ///     let next = db.find::<User>().after(&token).unwrap().page(50).unwrap();
+    /// }
+    /// ```
+    pub fn page(mut self, size: usize) -> Result<Page<V>> {
+        if self.sort.is_some() {
+            bail!("Keyset pagination follows the document id order and can't be sorted");
+        }
+
+        let items: Vec<_> = self.by_ref().take(size).collect();
+        let next = match (items.last(), self.next()) {
+            (Some((id, _)), Some(_)) => Some(encode_token(id)?),
+            _ => None,
+        };
+
+        Ok(Page { items, next })
+    }
+
+    fn decode(&self, data: &Data) -> Option<(Value, V)> {
+        let projection = match &self.projection {
+            Some(projection) => projection,
+            None => {
+                let fields = decode_fields_where(&self.serializer, data, |_| true).ok()?;
+                let document = Value::Object(fields);
+
+                if !self.queries.iter().all(|query| query.matches(&document)) {
+                    return None;
+                }
+                let value = V::deserialize(&document).ok()?;
+                return Some((document, value));
+            },
+        };
+
+        let mut paths: Vec<&str> = projection.iter().map(String::as_str).collect();
+        self.queries.iter().for_each(|query| query.paths(&mut paths));
+        if let Some((field, _)) = &self.sort {
+            paths.push(field);
+        }
+        paths.extend(self.extra_paths.iter().map(String::as_str));
+
+        let fields = decode_fields_where(&self.serializer, data, |key| {
+            paths.iter().any(|path| field::root(path) == key)
+        })
+        .ok()?;
+        let document = Value::Object(fields);
+
+        if !self.queries.iter().all(|query| query.matches(&document)) {
+            return None;
+        }
+
+        let mut projected = serde_json::Map::new();
+        for path in projection {
+            if let Some(value) = field::lookup(&document, path) {
+                field::insert_path(&mut projected, path, value.clone());
+            }
+        }
+
+        let value = V::deserialize(&Value::Object(projected)).ok()?;
+        Some((document, value))
+    }
+
+    /// Read the documents from the table on the first call
+    fn documents(&mut self) -> &mut std::vec::IntoIter<(String, Data)> {
+        if self.documents.is_none() {
+            let mut documents = match self.source.take() {
+                Some(source) => source(&self.queries),
+                None => Vec::new(),
+            };
+
+            if let Some(last) = self.after {
+                documents.retain(|(id, _)| LemonId::decode(id).is_some_and(|sf| sf > last));
+            }
+
+            if self.created != (Bound::Unbounded, Bound::Unbounded) {
+                let created = self.created;
+                documents.retain(|(id, _)| {
+                    LemonId::created_at(id).is_some_and(|time| created.contains(&time))
+                });
+            }
+
+            // Snowflake ids grow with time, so this is the creation order
+            documents.sort_by_cached_key(|(id, _)| LemonId::decode(id));
+            self.documents = Some(documents.into_iter());
+        }
+
+        self.documents.as_mut().unwrap()
+    }
+
+    /// The next document passing the queries and the filters
+    fn next_match(&mut self) -> Option<(String, Value, V)> {
+        while let Some((id, data)) = self.documents().next() {
+            let (document, value) = match self.decode(&data) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+
+            if self.filters.iter().all(|filter| filter(&value)) {
+                return Some((id, document, value));
+            }
+        }
+
+        None
+    }
+
+    fn next_sorted(&mut self) -> Option<(String, Value, V)> {
+        if self.sorted.is_none() {
+            let (field, order) = self.sort.clone()?;
+
+            let mut matches = Vec::new();
+            while let Some(item) = self.next_match() {
+                matches.push(item);
+            }
+
+            matches.sort_by(|(_, a, _), (_, b, _)| {
+                let ordering = field::total_order(
+                    field::lookup(a, &field),
+                    field::lookup(b, &field),
+                );
+                match order {
+                    Order::Asc => ordering,
+                    Order::Desc => ordering.reverse(),
+                }
+            });
+
+            self.sorted = Some(matches.into_iter());
+        }
+
+        self.sorted.as_mut()?.next()
+    }
+
+    /// The next document after sorting, skip and limit are applied. The
+    /// value tree of the decoded fields is returned along with `V`.
+    fn next_document(&mut self) -> Option<(String, Value, V)> {
+        if self.limit == Some(0) {
+            return None;
+        }
+
+        loop {
+            let item = match self.sort {
+                Some(_) => self.next_sorted()?,
+                None => self.next_match()?,
+            };
+
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+
+            if let Some(limit) = self.limit.as_mut() {
+                *limit -= 1;
+            }
+            return Some(item);
+        }
+    }
+}
+
+impl<V> Iterator for Cursor<V>
+where
+    V: DeserializeOwned,
+{
+    type Item = (String, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_document().map(|(id, _, value)| (id, value))
+    }
+}
+
+fn encode_token(id: &str) -> Result<String> {
+    let sf = LemonId::decode(id)
+        .with_context(|| format!("`{}` isn't a valid document id", id))?;
+    Ok(base64::encode_config(sf.to_string(), base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_token(token: &str) -> Result<u64> {
+    base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()
+        .and_then(|raw| String::from_utf8(raw).ok())
+        .and_then(|raw| raw.parse::<u64>().ok())
+        .with_context(|| format!("Invalid resume token `{}`", token))
+}
@@ -32,10 +32,8 @@ impl JsonSerializer {
     where 
         V: DeserializeOwned, 
     {
-        match serde_json::from_str(std::str::from_utf8(data).unwrap()) {
-            Ok(val) => Some(val),
-            Err(_) => None,
-        }
+        let data = std::str::from_utf8(data).ok()?;
+        serde_json::from_str(data).ok()
     }
 
     pub fn serialize<V>(&self, data: &V) -> Result<Vec<u8>, String>
@@ -99,7 +99,7 @@ impl LemonSerializer {
         #[allow(unreachable_patterns)]
         match self.serializer {
             Serializer::JSON => self.json.deserialize(data),
-            Serializer::YAML => self.json.deserialize(data),
+            Serializer::YAML => self.yaml.deserialize(data),
 
             // Default Serializer: JSON
             _ => self.json.deserialize(data)
@@ -34,10 +34,8 @@ impl YamlSerializer {
     where 
         V: DeserializeOwned,
     {
-        match serde_yaml::from_str(std::str::from_utf8(data).unwrap()) {
-            Ok(val) => Some(val),
-            Err(_) => None,
-        }
+        let data = std::str::from_utf8(data).ok()?;
+        serde_yaml::from_str(data).ok()
 
     }
 
@@ -0,0 +1,149 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use anyhow::Result;
+
+use crate::{
+    error::LemonError,
+    id::DEFAULT_EPOCH,
+    serializer::Serializer,
+    utils::now_timestamp,
+};
+
+/// Tells a database file with a header apart from the legacy ones,
+/// which start right away with the serialized data
+const MAGIC: &[u8; 8] = b"LEMONDB\0";
+
+/// The version of the file format written by this build. Bump it on any
+/// change older builds can't read, they reject the newer files.
+const FORMAT_VERSION: u16 = 1;
+
+const VERSION_OFFSET: usize = MAGIC.len();
+const SERIALIZER_OFFSET: usize = VERSION_OFFSET + 2;
+const CREATED_AT_OFFSET: usize = SERIALIZER_OFFSET + 1;
+const EPOCH_OFFSET: usize = CREATED_AT_OFFSET + 8;
+const CHECKSUM_OFFSET: usize = EPOCH_OFFSET + 8;
+
+/// The size of the header, the frames of the log follow it
+pub(crate) const SIZE: usize = CHECKSUM_OFFSET + 4;
+
+/// The metadata at the start of the database file. A rewrite keeps the
+/// creation time and stamps the current version and epoch. It is laid out as
+///
+/// | magic | version (u16 le) | serializer (u8) | created at (u64 le) |
+/// | epoch (u64 le) | crc32 of the previous fields (u32 le) |
+#[derive(Debug, Clone)]
+pub(crate) struct Header {
+    /// The serializer of every frame in the file
+    pub serializer: Serializer,
+    /// When the database was created, in milliseconds since the unix epoch
+    pub created_at: u64,
+}
+
+impl Header {
+
+    pub fn new(serializer: Serializer) -> Header {
+        Header {
+            serializer,
+            created_at: now_timestamp(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(SIZE);
+        raw.extend_from_slice(MAGIC);
+        raw.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        raw.push(serializer_id(&self.serializer));
+        raw.extend_from_slice(&self.created_at.to_le_bytes());
+        raw.extend_from_slice(&DEFAULT_EPOCH.to_le_bytes());
+        let checksum = crc32fast::hash(&raw);
+        raw.extend_from_slice(&checksum.to_le_bytes());
+        raw
+    }
+
+    /// Read the header at the start of the file, `None` if the file is
+    /// from before the headers.
+    ///
+    /// The version is checked before anything else, a newer version may
+    /// have changed the rest of the header.
+    pub fn decode(raw: &[u8]) -> Result<Option<Header>> {
+        if !raw.starts_with(MAGIC) {
+            return Ok(None);
+        }
+
+        let corrupted = |reason: &str| LemonError::Corrupted {
+            offset: 0,
+            frame: 0,
+            reason: reason.to_string(),
+        };
+
+        let version = match raw.get(VERSION_OFFSET..SERIALIZER_OFFSET) {
+            Some(version) => u16::from_le_bytes(version.try_into().unwrap()),
+            None => return Err(corrupted("incomplete header").into()),
+        };
+        if version > FORMAT_VERSION {
+            return Err(LemonError::UnsupportedVersion {
+                version,
+                supported: FORMAT_VERSION,
+            }.into());
+        }
+
+        let raw = match raw.get(..SIZE) {
+            Some(raw) => raw,
+            None => return Err(corrupted("incomplete header").into()),
+        };
+        let expected = u32::from_le_bytes(raw[CHECKSUM_OFFSET..].try_into().unwrap());
+        if crc32fast::hash(&raw[..CHECKSUM_OFFSET]) != expected {
+            return Err(corrupted("header checksum mismatch").into());
+        }
+
+        let serializer = match raw[SERIALIZER_OFFSET] {
+            0 => Serializer::JSON,
+            1 => Serializer::YAML,
+            id => return Err(corrupted(&format!("unknown serializer {}", id)).into()),
+        };
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(raw[offset..offset + 8].try_into().unwrap())
+        };
+
+        let epoch = u64_at(EPOCH_OFFSET);
+        if epoch != DEFAULT_EPOCH {
+            return Err(LemonError::UnsupportedEpoch {
+                epoch,
+                supported: DEFAULT_EPOCH,
+            }.into());
+        }
+
+        Ok(Some(Header {
+            serializer,
+            created_at: u64_at(CREATED_AT_OFFSET),
+        }))
+    }
+
+}
+
+/// The id of the serializer in the header, the same as `Serializer::from`
+fn serializer_id(serializer: &Serializer) -> u8 {
+    match serializer {
+        Serializer::JSON => 0,
+        Serializer::YAML => 1,
+    }
+}
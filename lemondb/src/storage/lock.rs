@@ -0,0 +1,161 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, Context};
+use fs2::FileExt;
+
+use crate::error::LemonError;
+
+/// How long to sleep between two attempts while waiting for the lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An exclusive advisory lock held on the sidecar `<db>.lock` file.
+///
+/// The database file itself can't be locked since every rewrite renames
+/// a new file over it. The lock is released when it's dropped, or by the
+/// system if the process dies. The sidecar file is left in place, removing
+/// it would let another process lock a file that no longer has the path.
+#[derive(Debug)]
+pub(crate) struct LemonLock {
+    file: File,
+}
+
+impl LemonLock {
+
+    /// Lock the database at `db_path`. Fail with `LemonError::Locked` right
+    /// away, or once `timeout` passed if another process holds the lock.
+    pub fn acquire(db_path: &Path, timeout: Option<Duration>) -> Result<LemonLock> {
+        let path = lock_path(db_path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open the lock file `{}`", path.display()))?;
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(LemonLock { file }),
+                Err(err) if err.kind() == fs2::lock_contended_error().kind() => {},
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("Failed to lock `{}`", path.display())
+                    });
+                },
+            }
+
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => thread::sleep(RETRY_INTERVAL),
+                _ => return Err(LemonError::Locked { path: db_path.to_path_buf() }.into()),
+            }
+        }
+    }
+
+}
+
+impl Drop for LemonLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// The sidecar lock file next to the database file
+fn lock_path(db_path: &Path) -> PathBuf {
+    let mut path = OsString::from(db_path.as_os_str());
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        LemonDb,
+        LemonError,
+        LemonStorageMode,
+        testing::{option, temp_db},
+    };
+
+    fn lemon_error(result: anyhow::Result<LemonDb>) -> LemonError {
+        let err = result.err().unwrap();
+        err.downcast_ref::<LemonError>().cloned().unwrap()
+    }
+
+    #[test]
+    fn second_open_is_locked() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.insert("a", &1).unwrap();
+
+        let expected = LemonError::Locked { path: path.clone() };
+        assert_eq!(lemon_error(LemonDb::open(&path, option(LemonStorageMode::SNAPSHOT))), expected);
+        assert_eq!(lemon_error(LemonDb::new(&path, option(LemonStorageMode::SNAPSHOT))), expected);
+        assert!(LemonDb::recover(&path, option(LemonStorageMode::SNAPSHOT)).is_err());
+    }
+
+    #[test]
+    fn lock_is_held_until_every_handle_is_dropped() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.insert("a", &1).unwrap();
+        let clone = db.clone();
+        let table = db.table("other").unwrap();
+
+        drop(db);
+        assert!(LemonDb::open(&path, option(LemonStorageMode::SNAPSHOT)).is_err());
+        drop(clone);
+        assert!(LemonDb::open(&path, option(LemonStorageMode::SNAPSHOT)).is_err());
+        drop(table);
+
+        let db = LemonDb::open(&path, option(LemonStorageMode::SNAPSHOT)).unwrap();
+        assert_eq!(db.get::<u32>("a").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn timeout_waits_for_the_lock() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::SNAPSHOT);
+        db.insert("a", &1).unwrap();
+
+        let mut waiting = option(LemonStorageMode::SNAPSHOT);
+        waiting.lock_timeout = Some(Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(matches!(lemon_error(LemonDb::open(&path, waiting.clone())), LemonError::Locked { .. }));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(db);
+        });
+        waiting.lock_timeout = Some(Duration::from_secs(10));
+        let db = LemonDb::open(&path, waiting).unwrap();
+        assert_eq!(db.get::<u32>("a").unwrap(), Some(1));
+        holder.join().unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    error::LemonError,
+    serializer::LemonSerializer,
+};
+
+type Data = HashMap<String, Vec<u8>>;
+type Document = HashMap<String, Data>;
+type Table = HashMap<String, Document>;
+
+/// The size of the length and of the checksum prefixing a frame
+const LENGTH_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 4;
+const HEADER_SIZE: usize = LENGTH_SIZE + CHECKSUM_SIZE;
+
+/// A change appended to the database file in `LemonStorageMode::LOG`.
+/// Replaying the records in order rebuilds the tables.
+///
+/// The records of a dump are appended together as one frame, so a dump is
+/// either replayed entirely or not at all. A frame is laid out as
+///
+/// | length (u32 le) | crc32 of the length and the body (u32 le) | body |
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum LogRecord {
+    /// The whole database, the log starts over from it
+    Snapshot(Vec<Table>),
+    Put { table: String, id: String, data: Data },
+    Delete { table: String, id: String },
+    Truncate { table: String },
+    CreateTable { table: String },
+    DropTable { table: String },
+    RenameTable { old: String, new: String },
+}
+
+impl LogRecord {
+
+    /// Apply the change to the tables
+    pub fn apply(self, map: &mut Vec<Table>) {
+        match self {
+            LogRecord::Snapshot(tables) => {
+                *map = tables;
+            },
+            LogRecord::Put { table, id, data } => {
+                table_mut(map, &table).insert(id, data);
+            },
+            LogRecord::Delete { table, id } => {
+                if let Some(documents) = map.iter_mut().find_map(|tables| tables.get_mut(&table)) {
+                    documents.remove(&id);
+                }
+            },
+            LogRecord::Truncate { table } => {
+                if let Some(documents) = map.iter_mut().find_map(|tables| tables.get_mut(&table)) {
+                    documents.clear();
+                }
+            },
+            LogRecord::CreateTable { table } => {
+                table_mut(map, &table);
+            },
+            LogRecord::DropTable { table } => {
+                for tables in map.iter_mut() {
+                    tables.remove(&table);
+                }
+            },
+            LogRecord::RenameTable { old, new } => {
+                if let Some(documents) = map.iter_mut().find_map(|tables| tables.remove(&old)) {
+                    *table_mut(map, &new) = documents;
+                }
+            },
+        }
+    }
+}
+
+/// The documents of the table, the table is created if it doesn't exist yet
+pub(crate) fn table_mut<'m>(map: &'m mut Vec<Table>, name: &str) -> &'m mut Document {
+    if !map.iter().any(|tables| tables.contains_key(name)) {
+        if map.is_empty() {
+            map.push(HashMap::new());
+        }
+        map[0].insert(name.to_string(), HashMap::new());
+    }
+
+    map.iter_mut()
+        .find_map(|tables| tables.get_mut(name))
+        .unwrap()
+}
+
+/// The result of replaying the frames of a log
+pub(crate) struct Replay {
+    pub tables: Vec<Table>,
+    /// The length of the file up to the end of the last snapshot, the
+    /// frames after it were appended since
+    pub snapshot: usize,
+    /// The length of the file up to the last valid frame
+    pub length: usize,
+    /// The first invalid frame, replaying stopped before it
+    pub corruption: Option<LemonError>,
+}
+
+/// Frame the records with their length and checksum
+pub(crate) fn encode(records: &[LogRecord], serializer: &LemonSerializer) -> Result<Vec<u8>> {
+    let body = serializer.serialize(&records).map_err(anyhow::Error::msg)?;
+    let length = (body.len() as u32).to_le_bytes();
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + body.len());
+    frame.extend_from_slice(&length);
+    frame.extend_from_slice(&checksum(&length, &body).to_le_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Replay the frames of the log from the byte `start` of the file until
+/// the end or the first invalid frame.
+///
+/// A frame running past the end of the file is invalid, whether it was cut
+/// short by a crash or its length was corrupted. The two can't be told
+/// apart, so neither is dropped silently.
+pub(crate) fn replay(raw: &[u8], start: usize, serializer: &LemonSerializer) -> Replay {
+    let mut replay = Replay {
+        tables: Vec::new(),
+        snapshot: start,
+        length: start,
+        corruption: None,
+    };
+
+    let mut frame = 0;
+    while replay.length < raw.len() {
+        let offset = replay.length;
+        let corrupted = |reason: &str| Some(LemonError::Corrupted {
+            offset: offset as u64,
+            frame,
+            reason: reason.to_string(),
+        });
+
+        let rest = &raw[offset..];
+        if rest.len() < HEADER_SIZE {
+            replay.corruption = corrupted("incomplete frame header");
+            break;
+        }
+
+        let length = u32::from_le_bytes(rest[..LENGTH_SIZE].try_into().unwrap()) as usize;
+        let body = match rest[HEADER_SIZE..].get(..length) {
+            Some(body) => body,
+            None => {
+                replay.corruption = corrupted(&format!(
+                    "the frame length {} runs past the end of the file",
+                    length
+                ));
+                break;
+            },
+        };
+
+        let expected = u32::from_le_bytes(rest[LENGTH_SIZE..HEADER_SIZE].try_into().unwrap());
+        if checksum(&rest[..LENGTH_SIZE], body) != expected {
+            replay.corruption = corrupted("checksum mismatch");
+            break;
+        }
+
+        let records = match serializer.deserialize::<Vec<LogRecord>>(body) {
+            Some(records) => records,
+            None => {
+                replay.corruption = corrupted("the records can't be deserialized");
+                break;
+            },
+        };
+
+        let snapshot = records.iter().any(|record| matches!(record, LogRecord::Snapshot(_)));
+        for record in records {
+            record.apply(&mut replay.tables);
+        }
+        replay.length = offset + HEADER_SIZE + length;
+        if snapshot {
+            replay.snapshot = replay.length;
+        }
+        frame += 1;
+    }
+
+    replay
+}
+
+fn checksum(length: &[u8], body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(length);
+    hasher.update(body);
+    hasher.finalize()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializer::Serializer;
+
+    fn frame(id: &str, serializer: &LemonSerializer) -> Vec<u8> {
+        let mut data = HashMap::new();
+        data.insert("key".to_string(), serializer.serialize(&id).unwrap());
+        let record = LogRecord::Put {
+            table: "_table".to_string(),
+            id: id.to_string(),
+            data,
+        };
+        encode(&[record], serializer).unwrap()
+    }
+
+    /// The log of three frames and the offset of each of them
+    fn log(serializer: &LemonSerializer) -> (Vec<u8>, Vec<usize>) {
+        let mut raw = Vec::new();
+        let mut offsets = Vec::new();
+        for id in ["a", "b", "c"] {
+            offsets.push(raw.len());
+            raw.extend(frame(id, serializer));
+        }
+        (raw, offsets)
+    }
+
+    fn ids(replay: &Replay) -> Vec<String> {
+        let mut ids: Vec<String> = replay.tables.iter()
+            .flat_map(|tables| tables.values())
+            .flat_map(|documents| documents.keys().cloned())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn corruption(replay: &Replay) -> (u64, usize, String) {
+        match &replay.corruption {
+            Some(LemonError::Corrupted { offset, frame, reason }) => (*offset, *frame, reason.clone()),
+            other => panic!("Expected a corruption, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replay_every_frame() {
+        let serializer = LemonSerializer::new(Serializer::JSON);
+        let (raw, _) = log(&serializer);
+
+        let replay = replay(&raw, 0, &serializer);
+        assert!(replay.corruption.is_none());
+        assert_eq!(replay.length, raw.len());
+        assert_eq!(ids(&replay), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn torn_tail_is_reported() {
+        let serializer = LemonSerializer::new(Serializer::JSON);
+        let (raw, offsets) = log(&serializer);
+        let torn = &raw[..raw.len() - 3];
+
+        let replay = replay(torn, 0, &serializer);
+        let (offset, frame, _) = corruption(&replay);
+        assert_eq!((offset, frame), (offsets[2] as u64, 2));
+        assert_eq!(replay.length, offsets[2]);
+        assert_eq!(ids(&replay), ["a", "b"]);
+    }
+
+    #[test]
+    fn corrupted_length_is_reported() {
+        let serializer = LemonSerializer::new(Serializer::JSON);
+        let (mut raw, offsets) = log(&serializer);
+        raw[offsets[1] + 3] ^= 0x01;
+
+        let replay = replay(&raw, 0, &serializer);
+        let (offset, frame, reason) = corruption(&replay);
+        assert_eq!((offset, frame), (offsets[1] as u64, 1));
+        assert!(reason.contains("past the end"), "{}", reason);
+        assert_eq!(replay.length, offsets[1]);
+        assert_eq!(ids(&replay), ["a"]);
+    }
+
+    #[test]
+    fn checksum_mismatch_is_reported() {
+        let serializer = LemonSerializer::new(Serializer::JSON);
+        let (mut raw, offsets) = log(&serializer);
+        raw[offsets[2] - 2] ^= 0x01;
+
+        let replay = replay(&raw, 0, &serializer);
+        let (offset, frame, reason) = corruption(&replay);
+        assert_eq!((offset, frame, reason.as_str()), (offsets[1] as u64, 1, "checksum mismatch"));
+        assert_eq!(ids(&replay), ["a"]);
+    }
+
+    #[test]
+    fn offsets_count_from_the_start_of_the_file() {
+        let serializer = LemonSerializer::new(Serializer::JSON);
+        let (log, offsets) = log(&serializer);
+        let mut raw = vec![0; 10];
+        raw.extend(&log[..log.len() - 1]);
+
+        let replay = replay(&raw, 10, &serializer);
+        let (offset, frame, _) = corruption(&replay);
+        assert_eq!((offset, frame), (10 + offsets[2] as u64, 2));
+    }
+}
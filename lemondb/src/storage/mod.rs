@@ -0,0 +1,468 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{PathBuf, Path};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{Result, Context};
+
+use crate::{
+    db::LemonStorageMode,
+    error::LemonError,
+    serializer::LemonSerializer,
+    serializer::Serializer
+};
+
+pub(crate) use crate::storage::log::{LogRecord, table_mut};
+use crate::storage::log::Replay;
+pub(crate) use crate::storage::lock::LemonLock;
+use crate::storage::header::Header;
+
+mod header;
+mod lock;
+mod log;
+
+
+#[derive(Debug, Clone)]
+pub struct LemonStorage {
+    db_path: PathBuf,
+    serializer: LemonSerializer,
+    header: Header,
+    /// The length of the file up to the end of the last snapshot
+    snapshot: u64,
+    /// The bytes appended to the file since the last snapshot
+    appended: u64,
+    pub mode: LemonStorageMode,
+}
+
+type Data = HashMap<String, Vec<u8>>;
+type Document = HashMap<String, Data>;
+type Table = HashMap<String, Document>;
+
+/// The tables read from the database file
+pub(crate) struct Loaded {
+    pub tables: Vec<Table>,
+    /// The corruption skipped by a recovery
+    pub corruption: Option<LemonError>,
+    /// The serializer of the file, given by its header
+    pub serializer: Serializer,
+    /// The file has no header yet, it must be rewritten before any append
+    pub legacy: bool,
+}
+
+/// Keep the temporary files of concurrent writes apart
+static WRITE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+impl LemonStorage {
+
+     pub fn new<P: AsRef<Path>>(
+        db: P,
+        s: Serializer,
+        mode: LemonStorageMode,
+    ) -> LemonStorage {
+
+         let mut db_path_buf = PathBuf::new();
+         db_path_buf.push(db);
+         LemonStorage {
+             db_path: db_path_buf,
+             serializer: LemonSerializer::new(s.clone()),
+             header: Header::new(s),
+             snapshot: 0,
+             appended: 0,
+             mode,
+         }
+    }
+
+    /// Read the tables from the database file by replaying its frames.
+    ///
+    /// The serializer is taken from the header of the file, the one given
+    /// to `new` is tried first on the legacy files without a header. Fail
+    /// with `LemonError::UnknownFormat` if no serializer can read them.
+    ///
+    /// Fail with `LemonError::Corrupted` at the first frame that is invalid,
+    /// a frame cut short by a crash included. With `recover` keep what was
+    /// read up to it instead. The file is left untouched either way, it's
+    /// up to the caller to rewrite it before appending anything.
+    pub(crate) fn read(&mut self, recover: bool) -> Result<Loaded> {
+        let raw = fs::read(&self.db_path)
+            .with_context(|| format!("Failed to read `{}`", self.db_path.display()))?;
+
+        let header = Header::decode(&raw)?;
+        let legacy = header.is_none();
+        let replay = match header {
+            Some(header) => {
+                self.serializer = LemonSerializer::new(header.serializer.clone());
+                self.header = header;
+                log::replay(&raw, header::SIZE, &self.serializer)
+            },
+            None => self.read_legacy(&raw)?,
+        };
+        let serializer = self.header.serializer.clone();
+        self.snapshot = replay.snapshot as u64;
+        self.appended = (replay.length - replay.snapshot) as u64;
+
+        if let Some(corruption) = replay.corruption {
+            if !recover {
+                return Err(corruption.into());
+            }
+
+            return Ok(Loaded {
+                tables: replay.tables,
+                corruption: Some(corruption),
+                serializer,
+                legacy,
+            });
+        }
+
+        Ok(Loaded {
+            tables: replay.tables,
+            corruption: None,
+            serializer,
+            legacy,
+        })
+    }
+
+    /// Read a file from before the headers, it holds either the frames or,
+    /// older still, the bare tables. Nothing tells which serializer wrote
+    /// it, so the other one is tried when the given one can't read it.
+    fn read_legacy(&mut self, raw: &[u8]) -> Result<Replay> {
+        for serializer in legacy_serializers(&self.header.serializer) {
+            let lemon = LemonSerializer::new(serializer.clone());
+            let mut replay = log::replay(raw, 0, &lemon);
+
+            if replay.corruption.is_some() && replay.length == 0 {
+                match lemon.deserialize::<Vec<Table>>(raw) {
+                    Some(tables) => replay = Replay {
+                        tables,
+                        snapshot: raw.len(),
+                        length: raw.len(),
+                        corruption: None,
+                    },
+                    None => continue,
+                }
+            }
+
+            self.serializer = lemon;
+            self.header.serializer = serializer;
+            return Ok(replay);
+        }
+
+        Err(LemonError::UnknownFormat { path: self.db_path.clone() }.into())
+    }
+
+    /// The size of the database file, `0` if it doesn't exist yet
+    pub(crate) fn size(&self) -> u64 {
+        fs::metadata(&self.db_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    }
+
+    /// The length of the file up to the end of the last snapshot
+    pub(crate) fn snapshot_size(&self) -> u64 {
+        self.snapshot
+    }
+
+    /// The bytes appended to the file since the last snapshot
+    pub(crate) fn appended(&self) -> u64 {
+        self.appended
+    }
+
+    /// Append the records to the end of the log as one frame and fsync it,
+    /// so the cost of a write only depends on the size of the change.
+    pub(crate) fn append(&mut self, records: &[LogRecord]) -> Result<()> {
+        let frames = log::encode(records, &self.serializer)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.db_path)
+            .with_context(|| format!("Failed to open `{}`", self.db_path.display()))?;
+        file.write_all(&frames).context("Failed to append to the log")?;
+        file.sync_data().context("Failed to sync the log")?;
+        self.appended += frames.len() as u64;
+        Ok(())
+    }
+
+    /// Replace the whole file with the header and the records
+    pub(crate) fn rewrite(&mut self, records: &[LogRecord]) -> Result<()> {
+        let mut data = self.header.encode();
+        data.extend(log::encode(records, &self.serializer)?);
+        self.replace(&data)?;
+        self.snapshot = data.len() as u64;
+        self.appended = 0;
+        Ok(())
+    }
+
+    /// Write the data to a temporary file in the same directory, fsync it
+    /// and rename it over the database file. The rename is atomic so the
+    /// file holds either the previous dump or the new one, never a part
+    /// of it. The directory is fsynced for the rename to survive a crash.
+    fn replace(&self, data: &[u8]) -> Result<()> {
+        let name = self.db_path.file_name()
+            .context("The database path has no file name")?;
+        let dir = match self.db_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            WRITE_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp = dir.join(temp_name);
+
+        let written = (|| -> Result<()> {
+            let mut file = File::create(&temp)
+                .with_context(|| format!("Failed to create `{}`", temp.display()))?;
+            if let Ok(metadata) = fs::metadata(&self.db_path) {
+                file.set_permissions(metadata.permissions())
+                    .context("Failed to keep the permissions of the database")?;
+            }
+
+            file.write_all(data).context("Failed to write the data")?;
+            file.sync_all().context("Failed to sync the data")?;
+            fs::rename(&temp, &self.db_path)
+                .with_context(|| format!("Failed to replace `{}`", self.db_path.display()))
+        })();
+
+        if written.is_err() {
+            // The previous dump is left untouched
+            let _ = fs::remove_file(&temp);
+        }
+        written?;
+
+        sync_dir(&dir)
+    }
+
+}
+
+/// The serializers to read a legacy file with, the given one first
+fn legacy_serializers(given: &Serializer) -> [Serializer; 2] {
+    match given {
+        Serializer::JSON => [Serializer::JSON, Serializer::YAML],
+        Serializer::YAML => [Serializer::YAML, Serializer::JSON],
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync the directory `{}`", dir.display()))
+}
+
+/// Directories can't be opened as files to be synced on other platforms
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    use super::{header, log};
+    use crate::{
+        LemonDb,
+        LemonError,
+        LemonOption,
+        LemonStorageMode,
+        Serializer,
+        serializer::LemonSerializer,
+        testing::{option, reopen, temp_db},
+    };
+
+    fn with_serializer(serializer: Serializer) -> LemonOption {
+        let mut option = option(LemonStorageMode::SNAPSHOT);
+        option.serializer = serializer;
+        option
+    }
+
+    /// A database written with the serializer, return its file
+    fn written_with(dir: &TempDir, serializer: Serializer) -> Vec<u8> {
+        let path = dir.path().join("written.db");
+        let mut db = LemonDb::new(&path, with_serializer(serializer)).unwrap();
+        db.insert("a", &1).unwrap();
+        drop(db);
+        fs::read(&path).unwrap()
+    }
+
+    fn open_err(path: &Path, serializer: Serializer) -> LemonError {
+        let err = LemonDb::open(path, with_serializer(serializer)).err().unwrap();
+        err.downcast_ref::<LemonError>().cloned().unwrap()
+    }
+
+    #[test]
+    fn log_replays_every_change_after_reopen() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::LOG);
+        db.insert("a", &1).unwrap();
+        let first = fs::read(&path).unwrap();
+
+        db.insert("b", &2).unwrap();
+        db.set("a", &10).unwrap();
+        db.remove("b").unwrap();
+
+        let mut renamed = db.table("renamed").unwrap();
+        renamed.insert("x", &1).unwrap();
+        db.rename_table("renamed", "moved").unwrap();
+        let mut truncated = db.table("truncated").unwrap();
+        truncated.insert("y", &1).unwrap();
+        db.truncate_table("truncated").unwrap();
+        db.table("dropped").unwrap().insert("z", &1).unwrap();
+        db.drop_table("dropped").unwrap();
+
+        // Every change after the first dump was appended
+        let log = fs::read(&path).unwrap();
+        assert!(log.len() > first.len());
+        assert_eq!(&log[..first.len()], &first[..]);
+
+        drop((db, renamed, truncated));
+        for mode in [LemonStorageMode::LOG, LemonStorageMode::SNAPSHOT] {
+            let db = reopen(&path, mode);
+            assert_eq!(db.get::<u32>("a").unwrap(), Some(10));
+            assert_eq!(db.get::<u32>("b").unwrap(), None);
+            assert_eq!(db.len(), 1);
+
+            let mut tables = db.tables();
+            tables.sort();
+            assert_eq!(tables, ["_table", "moved", "truncated"]);
+            assert_eq!(db.table("moved").unwrap().get::<u32>("x").unwrap(), Some(1));
+            assert!(db.table("truncated").unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn recover_leaves_the_file_until_the_next_dump() {
+        let (_dir, path, mut db) = temp_db(LemonStorageMode::LOG);
+        db.insert("a", &1).unwrap();
+        db.insert("b", &2).unwrap();
+        drop(db);
+
+        // A dump cut short by a crash
+        let mut raw = fs::read(&path).unwrap();
+        raw.extend([40, 0, 0, 0, 1, 2]);
+        fs::write(&path, &raw).unwrap();
+
+        assert!(LemonDb::open(&path, option(LemonStorageMode::LOG)).is_err());
+        let (mut db, corruption) = LemonDb::recover(&path, option(LemonStorageMode::LOG)).unwrap();
+        assert!(corruption.is_some());
+        assert_eq!(fs::read(&path).unwrap(), raw);
+
+        db.insert("c", &3).unwrap();
+        drop(db);
+        let db = reopen(&path, LemonStorageMode::LOG);
+        assert_eq!(db.get::<u32>("a").unwrap(), Some(1));
+        assert_eq!(db.get::<u32>("c").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn header_gives_the_serializer() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+        fs::write(&path, written_with(&dir, Serializer::YAML)).unwrap();
+
+        let mut db = LemonDb::open(&path, with_serializer(Serializer::JSON)).unwrap();
+        assert_eq!(db.get::<u32>("a").unwrap(), Some(1));
+        db.insert("b", &2).unwrap();
+        drop(db);
+
+        let db = LemonDb::open(&path, with_serializer(Serializer::YAML)).unwrap();
+        assert_eq!(db.get::<u32>("b").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn newer_files_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+        let raw = written_with(&dir, Serializer::JSON);
+
+        let mut newer = raw.clone();
+        newer[8..10].copy_from_slice(&2u16.to_le_bytes());
+        fs::write(&path, &newer).unwrap();
+        assert_eq!(
+            open_err(&path, Serializer::JSON),
+            LemonError::UnsupportedVersion { version: 2, supported: 1 },
+        );
+
+        let mut epoch = raw;
+        epoch[19..27].copy_from_slice(&42u64.to_le_bytes());
+        let checksum = crc32fast::hash(&epoch[..27]);
+        epoch[27..31].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&path, &epoch).unwrap();
+        assert!(matches!(
+            open_err(&path, Serializer::JSON),
+            LemonError::UnsupportedEpoch { epoch: 42, .. },
+        ));
+    }
+
+    #[test]
+    fn legacy_files_are_read_with_either_serializer() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+
+        for written in [Serializer::JSON, Serializer::YAML] {
+            // The frames without the header, and the bare tables before them
+            let lemon = LemonSerializer::new(written.clone());
+            let frames = written_with(&dir, written)[header::SIZE..].to_vec();
+            let tables = log::replay(&frames, 0, &lemon).tables;
+            let bare = lemon.serialize(&tables).unwrap();
+
+            for legacy in [frames, bare] {
+                for given in [Serializer::JSON, Serializer::YAML] {
+                    fs::write(&path, &legacy).unwrap();
+                    let mut db = LemonDb::open(&path, with_serializer(given)).unwrap();
+                    assert_eq!(db.get::<u32>("a").unwrap(), Some(1));
+
+                    // The next dump writes the header
+                    db.insert("b", &2).unwrap();
+                    drop(db);
+                    assert!(fs::read(&path).unwrap().starts_with(b"LEMONDB"));
+                    let db = reopen(&path, LemonStorageMode::SNAPSHOT);
+                    assert_eq!(db.get::<u32>("a").unwrap(), Some(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_files_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+        fs::write(&path, "not a database").unwrap();
+
+        for given in [Serializer::JSON, Serializer::YAML] {
+            assert_eq!(open_err(&path, given), LemonError::UnknownFormat { path: path.clone() });
+        }
+        assert!(LemonDb::recover(&path, with_serializer(Serializer::JSON)).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a database");
+    }
+}
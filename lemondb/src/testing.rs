@@ -0,0 +1,51 @@
+/*
+ *
+ * Copyright (c) 2022 riyuzenn
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * at your option) any later version.
+ *
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+*/
+
+
+//! Helpers shared by the tests of the modules
+
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+use crate::{LemonDb, LemonDumpRule, LemonOption, LemonStorageMode, Serializer};
+
+pub(crate) fn option(storage: LemonStorageMode) -> LemonOption {
+    LemonOption {
+        table_name: None,
+        dump_rule: LemonDumpRule::AUTO,
+        serializer: Serializer::JSON,
+        storage,
+        lock_timeout: None,
+    }
+}
+
+/// A new database in a temporary directory, removed with the `TempDir`
+pub(crate) fn temp_db(storage: LemonStorageMode) -> (TempDir, PathBuf, LemonDb) {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("test.db");
+    let db = LemonDb::new(&path, option(storage)).unwrap();
+    (dir, path, db)
+}
+
+/// Open the database again once every handle of it is dropped
+pub(crate) fn reopen(path: &Path, storage: LemonStorageMode) -> LemonDb {
+    LemonDb::open(path, option(storage)).unwrap()
+}